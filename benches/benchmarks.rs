@@ -61,7 +61,7 @@ fn reads_32_k(b: &mut Bencher) {
 }
 
 fn bench_write(b: &mut Bencher, db_name: String, bytesize: usize) {
-    let mut db = common::DatabaseTesting::new(db_name, ByteSize::mb(50).as_u64());
+    let db = common::DatabaseTesting::new(db_name, ByteSize::mb(50).as_u64());
 
     let key = b"name".to_vec();
     let val = vec![b'?'; bytesize];
@@ -75,7 +75,7 @@ fn bench_write(b: &mut Bencher, db_name: String, bytesize: usize) {
 }
 
 fn bench_read(b: &mut Bencher, db_name: String, bytesize: usize) {
-    let mut db = common::DatabaseTesting::new(db_name, ByteSize::mb(50).as_u64());
+    let db = common::DatabaseTesting::new(db_name, ByteSize::mb(50).as_u64());
 
     let key = b"name".to_vec();
     let val = vec![b'?'; bytesize];
@@ -1,10 +1,10 @@
-use bitcask;
 use bytesize::ByteSize;
 
 fn main() {
     let options = bitcask::Options {
         base_dir: std::path::PathBuf::from("./db1"),
         data_file_limit: ByteSize::mb(10).as_u64(),
+        ..Default::default()
     };
 
     let db = bitcask::new(options);
@@ -12,7 +12,7 @@ fn main() {
         println!("{}", err_msg);
         std::process::exit(1);
     }
-    let mut db = db.unwrap();
+    let db = db.unwrap();
 
     for n in 0..1000 {
         let name = format!("Peter Nr. {}", n);
@@ -23,17 +23,16 @@ fn main() {
 
     /*
     db.keys_range(b"name:1", b"name:4").for_each(|(key, _)| {
-        println!("key: {}", String::from_utf8_lossy(key));
+        println!("key: {}", String::from_utf8_lossy(&key));
     });
     */
 
     db.keys_range_min(b"name:999").for_each(|(key, _)| {
-        println!("key: {}", String::from_utf8_lossy(key));
+        println!("key: {}", String::from_utf8_lossy(&key));
     });
 
     /*
     db.keys()
-        .cloned()
         .filter(|key| String::from_utf8_lossy(&key).ends_with("99"))
         .for_each(|key| {
             println!("key: {}", String::from_utf8_lossy(&key));
@@ -0,0 +1,53 @@
+/// BatchOp is a single operation of a WriteBatch.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchOp {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+impl BatchOp {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Put { key, .. } => key,
+            BatchOp::Delete { key } => key,
+        }
+    }
+}
+
+/// WriteBatch groups puts and removes which are written with `Database::write_batch`.
+/// Either all of them are applied or, if the write gets interrupted, none of them.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+        self
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Delete { key: key.to_vec() });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+}
@@ -0,0 +1,102 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+/// EncryptionKey is the 256 bit key data and hint files are encrypted with.
+#[derive(Clone, Copy, PartialEq)]
+pub struct EncryptionKey(pub [u8; 32]);
+
+// keys must not end up in logs:
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// Cipher seals single records with ChaCha20-Poly1305. A sealed record is the random
+/// nonce followed by the ciphertext, authenticated together with the file it belongs
+/// to and its offset, so records can't be swapped or moved between files.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+
+    // plain files are refused once there is a key, unless they are being migrated:
+    pub accepts_plain_files: bool,
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Cipher(..)")
+    }
+}
+
+impl Cipher {
+    const NONCE_SIZE: usize = 12;
+
+    pub fn new(key: &EncryptionKey) -> Cipher {
+        Cipher {
+            aead: ChaCha20Poly1305::new(Key::from_slice(&key.0)),
+            accepts_plain_files: false,
+        }
+    }
+
+    pub fn accepting_plain_files(self, accepts_plain_files: bool) -> Cipher {
+        Cipher {
+            accepts_plain_files,
+            ..self
+        }
+    }
+
+    pub fn seal(&self, magic: [u8; 4], file_id: u128, offset: u64, plaintext: &[u8]) -> Vec<u8> {
+        // random nonces are fine, a key would have to seal ~2^32 records before a repeat is likely:
+        let nonce: [u8; Cipher::NONCE_SIZE] = rand::random();
+        let aad = Self::associated_data(magic, file_id, offset);
+
+        let ciphertext = self
+            .aead
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .expect("encrypting into a vec can't fail");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// open returns None if the record was sealed with another key or got modified.
+    pub fn open(
+        &self,
+        magic: [u8; 4],
+        file_id: u128,
+        offset: u64,
+        sealed: &[u8],
+    ) -> Option<Vec<u8>> {
+        if sealed.len() < Cipher::NONCE_SIZE {
+            return None;
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(Cipher::NONCE_SIZE);
+        let aad = Self::associated_data(magic, file_id, offset);
+
+        self.aead
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .ok()
+    }
+
+    fn associated_data(magic: [u8; 4], file_id: u128, offset: u64) -> Vec<u8> {
+        let mut aad = magic.to_vec();
+        aad.extend_from_slice(&file_id.to_le_bytes());
+        aad.extend_from_slice(&offset.to_le_bytes());
+        aad
+    }
+}
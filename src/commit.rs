@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+use crate::error::Error;
+use crate::ErrorResult;
+
+/// CommitQueue implements group commit: concurrent writers enqueue their writes and the
+/// first one in line becomes the leader, which commits everything queued so far at once.
+pub struct CommitQueue<T, R> {
+    queue: Mutex<VecDeque<Arc<Pending<T, R>>>>,
+    condvar: Condvar,
+}
+
+struct Pending<T, R> {
+    write: T,
+    // set by the leader which committed the write:
+    result: Mutex<Option<ErrorResult<R>>>,
+}
+
+impl<T, R> CommitQueue<T, R> {
+    pub fn new() -> CommitQueue<T, R> {
+        CommitQueue {
+            queue: Mutex::new(VecDeque::new()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// commit blocks until `write` got committed, by this thread or by another leader.
+    /// A leader hands all queued writes to `apply`, which returns one result per write.
+    pub fn commit<F>(&self, write: T, apply: F) -> ErrorResult<R>
+    where
+        F: FnOnce(&[&T]) -> ErrorResult<Vec<R>>,
+    {
+        let pending = Arc::new(Pending {
+            write,
+            result: Mutex::new(None),
+        });
+
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_back(Arc::clone(&pending));
+
+        loop {
+            if let Some(result) = pending.result.lock().unwrap().take() {
+                return result;
+            }
+
+            if Arc::ptr_eq(queue.front().unwrap(), &pending) {
+                break;
+            }
+
+            queue = self.condvar.wait(queue).unwrap();
+        }
+
+        // writes queued from now on stay behind the group and wait for the next leader:
+        let group = Group {
+            commit_queue: self,
+            pending: queue.iter().cloned().collect(),
+        };
+        drop(queue);
+
+        let writes: Vec<&T> = group.pending.iter().map(|pending| &pending.write).collect();
+        let results = apply(&writes).and_then(|results| {
+            if results.len() != writes.len() {
+                return Err(Error::GroupCommit {
+                    reason: format!("{} results for {} writes", results.len(), writes.len()),
+                });
+            }
+            Ok(results)
+        });
+
+        match results {
+            Ok(results) => {
+                let mut results = results.into_iter();
+                let own_result = results.next().expect("a result for every write");
+                for (pending, result) in group.pending.iter().skip(1).zip(results) {
+                    *pending.result.lock().unwrap() = Some(Ok(result));
+                }
+                Ok(own_result)
+            }
+            Err(err) => {
+                for pending in group.pending.iter().skip(1) {
+                    *pending.result.lock().unwrap() = Some(Err(err.clone()));
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Group is the writes a leader commits. Once it is dropped, even if the leader panics,
+/// the writes leave the queue, writes without a result fail and the followers wake up.
+struct Group<'a, T, R> {
+    commit_queue: &'a CommitQueue<T, R>,
+    pending: Vec<Arc<Pending<T, R>>>,
+}
+
+impl<T, R> Drop for Group<'_, T, R> {
+    fn drop(&mut self) {
+        let mut queue = self
+            .commit_queue
+            .queue
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        queue.drain(..self.pending.len());
+
+        for pending in self.pending.iter().skip(1) {
+            let mut result = pending
+                .result
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if result.is_none() {
+                *result = Some(Err(Error::GroupCommit {
+                    reason: "the leader of the group failed".to_owned(),
+                }));
+            }
+        }
+
+        self.commit_queue.condvar.notify_all();
+    }
+}
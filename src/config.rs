@@ -1,6 +1,39 @@
+// Deletes used to be stored as this value. Only needed to read version 1 data files:
 pub const REMOVE_TOMBSTONE: &[u8] = b"%_%_%_%<!(R|E|M|O|V|E|D)!>%_%_%_%_";
 pub static DATA_FILE_GLOB_FORMAT: &str = "data.*";
 
 pub fn data_file_format(id: u128) -> String {
     format!("data.{}", id)
 }
+
+pub fn index_file_format(id: u128) -> String {
+    format!("index.{}", id)
+}
+
+// hint files are written under this name first and renamed once complete:
+pub static INDEX_TMP_FILE_GLOB_FORMAT: &str = "index-tmp.*";
+
+pub fn index_tmp_file_format(id: u128) -> String {
+    format!("index-tmp.{}", id)
+}
+
+// held by the process which writes to the database directory:
+pub static LOCK_FILE_NAME: &str = "lock";
+
+// data files are rewritten under this name by a key rotation and renamed once complete:
+pub static REKEY_FILE_GLOB_FORMAT: &str = "rekey.*";
+
+pub fn rekey_file_format(id: u128) -> String {
+    format!("rekey.{}", id)
+}
+
+// merges write their output under this name, it is renamed once the merge is committed:
+pub static MERGE_FILE_GLOB_FORMAT: &str = "merge.*";
+
+pub fn merge_file_format(id: u128) -> String {
+    format!("merge.{}", id)
+}
+
+// intent of a running merge, see MergeManifest:
+pub static MERGE_MANIFEST_FILE_NAME: &str = "merge-manifest";
+pub static MERGE_MANIFEST_TMP_FILE_NAME: &str = "merge-manifest.tmp";
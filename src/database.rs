@@ -4,368 +4,1461 @@ extern crate lru;
 use log::*;
 use lru::LruCache;
 
+use std::collections::{HashMap, HashSet};
 use std::fs::create_dir_all;
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use glob::glob;
 use rayon::prelude::*;
-use string_error::new_err;
 
+use crate::batch::{BatchOp, WriteBatch};
+use crate::cipher::{Cipher, EncryptionKey};
+use crate::commit::CommitQueue;
+use crate::datafile::Codec;
 use crate::datafile::DataFile;
 use crate::datafile::DataFileMetadata;
+use crate::datafile::Entry;
+use crate::datafile::EntryKind;
 use crate::error::*;
+use crate::header::FileHeader;
+use crate::indexfile::IndexEntry;
 use crate::indexfile::IndexFile;
 use crate::keydir::KeyDir;
 use crate::keydir::KeyDirEntry;
+use crate::lockfile::LockFile;
+use crate::manifest::MergeManifest;
+use crate::throttle::Throttle;
 use crate::ErrorResult;
 
 #[derive(Clone, Debug)]
 pub struct Options {
     pub base_dir: std::path::PathBuf,
     pub data_file_limit: u64,
+
+    // opens an existing database without taking its lock. Nothing in base_dir
+    // gets created, deleted or renamed and all writes fail with Error::ReadOnly:
+    pub read_only: bool,
+
+    // when writes to the active data file are flushed to disk:
+    pub sync_policy: SyncPolicy,
+
+    // what happens with files in base_dir which look like ours but aren't, ie. 'data.foo':
+    pub unknown_files: UnknownFilePolicy,
+
+    // values of at least compression_min_size bytes are stored compressed:
+    pub compression: Compression,
+    pub compression_min_size: u64,
+
+    // new data and hint files are encrypted with this key. Encrypted files can't be
+    // opened without it, plain ones are refused with it unless allow_plain_files is set:
+    pub encryption_key: Option<EncryptionKey>,
+    // keeps plain files readable with an encryption key, ie. while migrating a store.
+    // rotate_key encrypts them:
+    pub allow_plain_files: bool,
+
+    // merges run automatically in the background once the policy triggers:
+    pub merge_policy: Option<MergePolicy>,
+
+    // merges copy records at most this fast:
+    pub merge_throttle: MergeThrottle,
+}
+
+/// SyncPolicy decides when the active data file gets fsynced. Rotated data files
+/// and merge output are synced once they are complete, no matter the policy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    // every write returns once it is on disk:
+    Always,
+    // a background thread syncs every n milliseconds, with 0 it is the same as Always:
+    IntervalMs(u64),
+    // a background thread syncs once n bytes were written:
+    Bytes(u64),
+    // left to the OS, only `sync`, `close` and rotating a data file sync:
+    Never,
+}
+
+/// UnknownFilePolicy decides how startup and merge treat files which match the names of
+/// data or hint files but carry no valid id. They are never read, renamed or deleted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnknownFilePolicy {
+    // log a warning and leave the file alone:
+    Skip,
+    // refuse to open the database with Error::InvalidFileName:
+    Fail,
+}
+
+/// Compression is the codec new values are compressed with. Every record carries its
+/// codec, so changing it leaves existing records readable and merge recompresses them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+    // with the zstd level, ie. 3:
+    Zstd(i32),
+}
+
+/// MergePolicy lets a background thread merge the database once any of the triggers
+/// fires. Only immutable data files are taken into account.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergePolicy {
+    // share of dead bytes, from 0.0 to 1.0:
+    pub fragmentation: Option<f64>,
+    pub dead_bytes: Option<u64>,
+    pub immutable_files: Option<u64>,
+
+    // merges only start between these hours of the day (UTC), ie. (22, 4) for 22:00
+    // to 3:59. With the same start and end hour they can start at any time:
+    pub window: Option<(u8, u8)>,
+
+    // how often the triggers are checked:
+    pub check_interval: Duration,
+}
+
+impl Default for MergePolicy {
+    fn default() -> MergePolicy {
+        MergePolicy {
+            fragmentation: None,
+            dead_bytes: None,
+            immutable_files: None,
+            window: None,
+            check_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl MergePolicy {
+    /// is_due tells whether any of the triggers fires for these data files.
+    pub fn is_due(&self, data_files: &[DataFileMetadata]) -> bool {
+        let total_bytes: u64 = data_files.iter().map(|df| df.total_bytes).sum();
+        let dead_bytes: u64 = data_files.iter().map(|df| df.dead_bytes()).sum();
+        let fragmentation = match total_bytes {
+            0 => 0.0,
+            total_bytes => dead_bytes as f64 / total_bytes as f64,
+        };
+
+        self.fragmentation.is_some_and(|max| fragmentation >= max)
+            || self.dead_bytes.is_some_and(|max| dead_bytes >= max)
+            || self
+                .immutable_files
+                .is_some_and(|max| data_files.len() as u64 >= max)
+    }
+
+    /// is_in_window tells whether a merge may start at `unix_secs`.
+    pub fn is_in_window(&self, unix_secs: u64) -> bool {
+        let (start, end) = match self.window {
+            Some((start, end)) if start != end => (u64::from(start), u64::from(end)),
+            _ => return true,
+        };
+
+        let hour = unix_secs / 3600 % 24;
+        if start < end {
+            start <= hour && hour < end
+        } else {
+            // the window spans midnight:
+            hour >= start || hour < end
+        }
+    }
+}
+
+/// MergeThrottle limits the I/O of merges, so they don't starve reads and writes.
+/// Without any limit merges copy records as fast as the disk allows.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MergeThrottle {
+    // bytes of the merged records per second:
+    pub bytes_per_sec: Option<u64>,
+    pub records_per_sec: Option<u64>,
+}
+
+/// MergeProgress tells how far the running merge got. Records are the live records and
+/// tombstones which get copied into the merged data file.
+#[derive(Clone, Debug)]
+pub struct MergeProgress {
+    pub bytes_processed: u64,
+    pub bytes_total: u64,
+    pub records_processed: u64,
+    pub records_total: u64,
+    pub files_done: u64,
+    pub files_total: u64,
+    // live records copied into the merged data file:
+    pub keys_rewritten: u64,
+    // bytes of the data files done less the size of their records in the merged one:
+    pub bytes_reclaimed: u64,
+    // once the merge started to copy records:
+    pub started_at: Instant,
+}
+
+impl MergeProgress {
+    pub fn bytes_remaining(&self) -> u64 {
+        self.bytes_total.saturating_sub(self.bytes_processed)
+    }
+
+    /// eta estimates the time left from the rate of the merge so far. It is None until
+    /// the first record got copied.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.records_processed == 0 {
+            return None;
+        }
+
+        let elapsed = self.started_at.elapsed().as_secs_f64();
+        let remaining = match self.bytes_total {
+            0 => {
+                (self.records_total - self.records_processed) as f64 / self.records_processed as f64
+            }
+            _ => self.bytes_remaining() as f64 / self.bytes_processed.max(1) as f64,
+        };
+        Some(Duration::from_secs_f64(elapsed * remaining))
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            base_dir: std::path::PathBuf::from("./db"),
+            data_file_limit: 10 * 1024 * 1024,
+            read_only: false,
+            sync_policy: SyncPolicy::Never,
+            unknown_files: UnknownFilePolicy::Skip,
+            compression: Compression::None,
+            compression_min_size: 256,
+            encryption_key: None,
+            allow_plain_files: false,
+            merge_policy: None,
+            merge_throttle: MergeThrottle::default(),
+        }
+    }
 }
 
+/// Database is a cheap to clone handle which can be shared between threads. Reads
+/// run concurrently, writes are appended to the active data file one at a time.
+#[derive(Clone)]
 pub struct Database {
+    inner: Arc<DatabaseInner>,
+}
+
+struct DatabaseInner {
     options: Options,
 
-    keydir: KeyDir,
+    keydir: RwLock<KeyDir>,
 
-    // active file:
-    current_data_file: DataFile,
+    // active file, held for the whole write so only one writer appends at a time.
+    // It is gone once the database got closed and read-only databases never have one:
+    active: Mutex<Option<ActiveDataFile>>,
 
     // datafiles
-    data_files: Vec<DataFileMetadata>,
-    data_files_cache: LruCache<u128, DataFile>,
+    data_files: RwLock<Vec<DataFileMetadata>>,
+    data_files_cache: Mutex<LruCache<u128, Arc<DataFile>>>,
 
     // once the active DataFile has reached the threshold
     // defined in data_file_limit, it will open a new data_file:
     data_file_limit: u64,
+
+    // concurrent writes are appended (and synced) together:
+    commit_queue: CommitQueue<WriteRequest, ErrorResult<bool>>,
+
+    // wakes up the background sync thread of the interval and bytes policies:
+    sync_signal: Arc<Signal>,
+
+    // wakes up the background merge thread of the merge policy:
+    merge_signal: Arc<Signal>,
+    merges_paused: AtomicBool,
+    // held by the running merge:
+    merge_lock: Mutex<()>,
+    // stops the running merge once the database gets closed:
+    closing: CancellationToken,
+    merge_progress: Mutex<Option<MergeProgress>>,
+
+    // built from the encryption key of the options:
+    cipher: Option<Cipher>,
 }
 
-pub fn new(options: Options) -> ErrorResult<Database> {
+impl Drop for DatabaseInner {
+    fn drop(&mut self) {
+        self.sync_signal.stop();
+        self.merge_signal.stop();
+    }
+}
+
+/// Signal wakes up a background thread, either on request or once its interval is over.
+#[derive(Default)]
+struct Signal {
+    // (requested, stopped):
+    state: Mutex<(bool, bool)>,
+    condvar: Condvar,
+}
+
+impl Signal {
+    fn request(&self) {
+        self.state.lock().unwrap().0 = true;
+        self.condvar.notify_one();
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.condvar.notify_one();
+    }
+
+    /// wait blocks until a run is requested or the interval is over. Returns false once stopped.
+    fn wait(&self, interval: Option<Duration>) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        while !state.0 && !state.1 {
+            match interval {
+                Some(interval) => {
+                    let (guard, timeout) = self.condvar.wait_timeout(state, interval).unwrap();
+                    state = guard;
+                    if timeout.timed_out() {
+                        break;
+                    }
+                }
+                None => state = self.condvar.wait(state).unwrap(),
+            }
+        }
+
+        state.0 = false;
+        !state.1
+    }
+}
+
+// a write waiting for the group commit:
+struct WriteRequest {
+    ops: Vec<BatchOp>,
+    // batches are written with a leading batch marker:
+    is_batch: bool,
+    // puts of the request expire at this time:
+    expires_at: Option<u128>,
+    // compare and swaps are only applied if their key holds this value (None: is absent):
+    expected: Option<Option<Vec<u8>>>,
+}
+
+struct ActiveDataFile {
+    data_file: DataFile,
+    // live and dead records of the active file, it moves to data_files on rotation:
+    metadata: DataFileMetadata,
+    // hints for the records of the active file, written to disk once it gets rotated:
+    hints: Vec<IndexEntry>,
+    // dropped last, after the data file got synced:
+    _lock: LockFile,
+}
+
+pub fn new(mut options: Options) -> ErrorResult<Database> {
     // best effort:
     let _ = env_logger::try_init();
 
-    create_dir_all(&options.base_dir).map_err(|source| Error::CreateDatabaseDir {
-        path: options.base_dir.to_path_buf(),
-        source,
-    })?;
-
-    let created_dir = create_dir_all(&options.base_dir);
-    if let Err(err_msg) = created_dir {
-        return Err(new_err(&format!(
-            "Failed to create '{}': {}",
-            options.base_dir.display(),
-            err_msg
-        )));
+    // a sync thread without an interval would never sleep:
+    if options.sync_policy == SyncPolicy::IntervalMs(0) {
+        options.sync_policy = SyncPolicy::Always;
     }
 
     let path = std::path::Path::new(&options.base_dir);
+    let cipher = options
+        .encryption_key
+        .as_ref()
+        .map(|key| Cipher::new(key).accepting_plain_files(options.allow_plain_files));
+
+    let active = if options.read_only {
+        if !path.is_dir() {
+            return Err(Error::DatabaseDirNotFound {
+                path: path.to_path_buf(),
+            });
+        }
+        None
+    } else {
+        create_dir_all(path).map_err(|source| Error::CreateDatabaseDir {
+            path: path.to_path_buf(),
+            source: Arc::new(source),
+        })?;
+
+        // before anything gets written, the directory has to be ours:
+        let lock = LockFile::acquire(path)?;
+
+        let filename = crate::config::data_file_format(crate::utils::time());
+        let data_file = DataFile::create(&path.join(filename), false, cipher.as_ref())?;
+        let metadata = DataFileMetadata::new(data_file.id, data_file.path.clone());
+
+        Some(ActiveDataFile {
+            data_file,
+            metadata,
+            hints: Vec::new(),
+            _lock: lock,
+        })
+    };
 
-    let filename = crate::config::data_file_format(crate::utils::time());
-    let data_file = DataFile::create(&path.join(filename), false)?;
-
-    let mut db = Database {
-        options: options.clone(),
-        keydir: KeyDir::new(),
-        current_data_file: data_file,
-        data_files: Vec::new(),
-        data_files_cache: LruCache::new(128),
-        data_file_limit: options.data_file_limit,
+    let db = Database {
+        inner: Arc::new(DatabaseInner {
+            options: options.clone(),
+            keydir: RwLock::new(KeyDir::new()),
+            active: Mutex::new(active),
+            data_files: RwLock::new(Vec::new()),
+            data_files_cache: Mutex::new(LruCache::new(128)),
+            data_file_limit: options.data_file_limit,
+            commit_queue: CommitQueue::new(),
+            sync_signal: Arc::new(Signal::default()),
+            merge_signal: Arc::new(Signal::default()),
+            merges_paused: AtomicBool::new(false),
+            merge_lock: Mutex::new(()),
+            closing: CancellationToken::new(),
+            merge_progress: Mutex::new(None),
+            cipher,
+        }),
     };
 
-    db.startup(&path)?;
+    db.startup(path)?;
+
+    match options.sync_policy {
+        SyncPolicy::IntervalMs(ms) if !options.read_only => {
+            spawn_sync_thread(&db, Some(Duration::from_millis(ms)))?
+        }
+        SyncPolicy::Bytes(_) if !options.read_only => spawn_sync_thread(&db, None)?,
+        _ => {}
+    }
+
+    if let Some(policy) = &options.merge_policy {
+        if !options.read_only {
+            spawn_merge_thread(&db, policy.clone())?;
+        }
+    }
 
     Ok(db)
 }
 
+/// spawn_sync_thread runs the background syncs. The thread only holds a weak reference,
+/// so it stops once the last handle of the database is dropped.
+fn spawn_sync_thread(db: &Database, interval: Option<Duration>) -> ErrorResult<()> {
+    let inner: Weak<DatabaseInner> = Arc::downgrade(&db.inner);
+    let signal = Arc::clone(&db.inner.sync_signal);
+
+    std::thread::Builder::new()
+        .name("bitcask-sync".to_owned())
+        .spawn(move || {
+            while signal.wait(interval) {
+                let db = match inner.upgrade() {
+                    Some(inner) => Database { inner },
+                    None => return,
+                };
+
+                if let Err(err) = db.sync_unsynced() {
+                    warn!(
+                        "Database.sync_thread: failed to sync the active data file: {}",
+                        err
+                    );
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// spawn_merge_thread checks the merge policy right away and then every check_interval.
+/// Like the sync thread it stops once the last handle of the database is dropped.
+fn spawn_merge_thread(db: &Database, policy: MergePolicy) -> ErrorResult<()> {
+    let inner: Weak<DatabaseInner> = Arc::downgrade(&db.inner);
+    let signal = Arc::clone(&db.inner.merge_signal);
+    signal.request();
+
+    std::thread::Builder::new()
+        .name("bitcask-merge".to_owned())
+        .spawn(move || {
+            while signal.wait(Some(policy.check_interval)) {
+                let db = match inner.upgrade() {
+                    Some(inner) => Database { inner },
+                    None => return,
+                };
+
+                if db.inner.merges_paused.load(Ordering::SeqCst) {
+                    continue;
+                }
+
+                let now = crate::utils::time() / 1_000_000_000;
+                if !policy.is_in_window(now as u64) {
+                    continue;
+                }
+
+                let data_files = db.inner.data_files.read().unwrap().clone();
+                if !policy.is_due(&data_files) {
+                    continue;
+                }
+
+                trace!("Database.merge_thread: merge policy triggered, merging now");
+                match db.merge() {
+                    Ok(report) => trace!("Database.merge_thread: merged: {:?}", report),
+                    // nothing can be merged anymore:
+                    Err(Error::Closed) => return,
+                    Err(err) => warn!("Database.merge_thread: merge failed: {}", err),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+/// rotate_key rewrites all data and hint files of a database which is not opened, from
+/// `options.encryption_key` to `new_key`. Without an old key plain files get encrypted,
+/// without a new key the files are decrypted. Files are replaced one at a time, so an
+/// interrupted rotation can be run again, files which are already rotated are skipped.
+pub fn rotate_key(options: &Options, new_key: Option<EncryptionKey>) -> ErrorResult<()> {
+    let base_dir = &options.base_dir;
+    if !base_dir.is_dir() {
+        return Err(Error::DatabaseDirNotFound {
+            path: base_dir.to_path_buf(),
+        });
+    }
+
+    let _lock = LockFile::acquire(base_dir)?;
+    recover_merge(base_dir, options.unknown_files)?;
+
+    // rotating is how plain files get encrypted, it always reads them:
+    let old_cipher = options
+        .encryption_key
+        .as_ref()
+        .map(|key| Cipher::new(key).accepting_plain_files(true));
+    let new_cipher = new_key.as_ref().map(Cipher::new);
+
+    let paths = glob_files_with_id(
+        base_dir,
+        crate::config::DATA_FILE_GLOB_FORMAT,
+        options.unknown_files,
+    )?;
+    for path in paths {
+        if is_rotated(&path, new_cipher.as_ref()) {
+            trace!(
+                "rotate_key: skipping '{}', it is already rotated",
+                path.display()
+            );
+            continue;
+        }
+
+        rotate_data_file(base_dir, &path, old_cipher.as_ref(), new_cipher.as_ref()).map_err(
+            |source| Error::FileLoad {
+                path: path.clone(),
+                source: Box::new(source),
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// recover_merge finishes a merge which got interrupted after its manifest was written
+/// and rolls back any other one by removing its output.
+fn recover_merge(base_dir: &Path, unknown_files: UnknownFilePolicy) -> ErrorResult<()> {
+    if let Some(manifest) = MergeManifest::read(base_dir)? {
+        if manifest.is_installable(base_dir) {
+            warn!(
+                "recover_merge: finishing an interrupted merge of the data files {:?}",
+                manifest.replaced_ids
+            );
+            manifest.install(base_dir)?;
+            manifest.finish(base_dir)?;
+        } else {
+            warn!(
+                "recover_merge: merged data file {:?} is missing, keeping the data files {:?}",
+                manifest.merged_id, manifest.replaced_ids
+            );
+            std::fs::remove_file(base_dir.join(crate::config::MERGE_MANIFEST_FILE_NAME))?;
+        }
+    }
+    let _ = std::fs::remove_file(base_dir.join(crate::config::MERGE_MANIFEST_TMP_FILE_NAME));
+
+    roll_back_merges(base_dir, unknown_files)
+}
+
+/// roll_back_merges removes the output of merges which never got a manifest.
+fn roll_back_merges(base_dir: &Path, unknown_files: UnknownFilePolicy) -> ErrorResult<()> {
+    let merge_paths = glob_files_with_id(
+        base_dir,
+        crate::config::MERGE_FILE_GLOB_FORMAT,
+        unknown_files,
+    )?;
+    for path in merge_paths {
+        warn!(
+            "roll_back_merges: rolling back an unfinished merge, removing '{}'",
+            path.display()
+        );
+        std::fs::remove_file(&path)?;
+
+        let file_id = crate::utils::extract_id_from_filename(&path)?;
+        let _ = std::fs::remove_file(base_dir.join(crate::config::index_tmp_file_format(file_id)));
+        if !base_dir
+            .join(crate::config::data_file_format(file_id))
+            .exists()
+        {
+            let _ = std::fs::remove_file(base_dir.join(crate::config::index_file_format(file_id)));
+        }
+    }
+    crate::utils::sync_dir(base_dir)?;
+
+    Ok(())
+}
+
+// a file is rotated if it is in the format of the new key and its first record opens with it:
+fn is_rotated(path: &Path, new_cipher: Option<&Cipher>) -> bool {
+    match DataFile::create(path, true, new_cipher) {
+        Ok(data_file) => {
+            data_file.is_encrypted() == new_cipher.is_some()
+                && data_file
+                    .iter_raw()
+                    .is_ok_and(|mut records| records.next().is_none_or(|record| record.is_ok()))
+        }
+        Err(_) => false,
+    }
+}
+
+fn rotate_data_file(
+    base_dir: &Path,
+    path: &Path,
+    old_cipher: Option<&Cipher>,
+    new_cipher: Option<&Cipher>,
+) -> ErrorResult<()> {
+    let old_data_file = DataFile::create(path, true, old_cipher)?;
+    let file_id = old_data_file.id;
+
+    let tmp_path = base_dir.join(crate::config::rekey_file_format(file_id));
+    let _ = std::fs::remove_file(&tmp_path);
+    let mut new_data_file = DataFile::create(&tmp_path, false, new_cipher)?;
+
+    // records are copied as they are stored, compressed values stay compressed:
+    let mut records = Vec::new();
+    for record in old_data_file.iter_raw()? {
+        records.push(record?.1);
+        if records.len() >= 1024 {
+            new_data_file.append(&records)?;
+            records.clear();
+        }
+    }
+    new_data_file.append(&records)?;
+    new_data_file.sync()?;
+    let hints = new_data_file.read_hints(false)?;
+
+    // the old hint file points to the old offsets. Without any hint file startup reads the
+    // data file, therefore it is removed before and written again after the data file:
+    let index_path = base_dir.join(crate::config::index_file_format(file_id));
+    if index_path.exists() {
+        std::fs::remove_file(&index_path)?;
+    }
+
+    // renamed before it is dropped, as empty files are removed on drop:
+    std::fs::rename(&tmp_path, path)?;
+    drop(new_data_file);
+
+    IndexFile::write_hints(base_dir, file_id, &hints, new_cipher)?;
+
+    trace!(
+        "rotate_key: rewrote '{}' with {} records",
+        path.display(),
+        hints.len()
+    );
+
+    Ok(())
+}
+
+/// MergeReport tells what a merge did.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MergeReport {
+    pub files_merged: u64,
+    // delete records of the merged files which got dropped:
+    pub tombstones_purged: u64,
+    // deletes and expired keys which still hide older records of files that weren't merged:
+    pub tombstones_kept: u64,
+    // bytes of the merged data files less the size of the merged one:
+    pub bytes_reclaimed: u64,
+}
+
+/// CancellationToken stops a merge started with `merge_with`. Clones share their state,
+/// so a token can be cancelled from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// state of a running merge:
+struct MergeRun<'a> {
+    progress: MergeProgress,
+    throttle: Throttle,
+    cancel: &'a CancellationToken,
+    on_progress: &'a mut dyn FnMut(&MergeProgress),
+}
+
 pub struct Stats {
     pub num_immutable_datafiles: u64,
     pub num_keys: u64,
+    // live and dead records of every data file, the active one is last:
+    pub data_files: Vec<DataFileMetadata>,
 }
 
 impl Database {
     pub fn stats(&self) -> Stats {
-        trace!("Stats called number of data files: {:?}", self.data_files);
+        let active = self
+            .inner
+            .active
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|active| active.metadata.clone());
+
+        let mut data_files = {
+            let data_files = self.inner.data_files.read().unwrap();
+            trace!("Stats called number of data files: {:?}", data_files);
+            data_files.clone()
+        };
+        let num_immutable_datafiles = data_files.len() as u64;
+        data_files.sort_by_key(|df| df.id);
+        data_files.extend(active);
 
         Stats {
-            num_immutable_datafiles: (self.data_files.len() as u64),
-            num_keys: (self.keydir.iter().count() as u64),
+            num_immutable_datafiles,
+            num_keys: (self.inner.keydir.read().unwrap().iter().count() as u64),
+            data_files,
         }
     }
 
     // Startup Jobs:
-    pub fn startup(&mut self, base_dir: &Path) -> ErrorResult<()> {
-        let mut data_files_sorted = self.get_data_files_except_current(&base_dir)?;
+    pub fn startup(&self, base_dir: &Path) -> ErrorResult<()> {
+        let active = self.inner.active.lock().unwrap();
+        // read-only databases have no current data file, all of them are immutable:
+        let current_id = active.as_ref().map(|active| active.data_file.id);
+
+        // read-only databases leave an interrupted merge to the next writer:
+        if !self.inner.options.read_only {
+            recover_merge(base_dir, self.inner.options.unknown_files)?;
+        }
 
-        self.build_keydir(&mut data_files_sorted)
+        let mut data_files_sorted = self.get_data_files_except_current(base_dir, current_id)?;
+
+        for path in &data_files_sorted {
+            // data files without a hint file were active in an earlier run. Their ids don't
+            // tell which one was active last, merged files are newer than the active file:
+            let file_id = crate::utils::extract_id_from_filename(path)?;
+            let index_path = base_dir.join(crate::config::index_file_format(file_id));
+            if !self.inner.options.read_only && !index_path.exists() {
+                self.recover_torn_write(path)?;
+            }
+        }
+
+        self.build_keydir(current_id, &mut data_files_sorted)
             .map_err(|source| Error::KeyDirFill {
                 path: base_dir.to_path_buf(),
-                source,
+                source: Box::new(source),
             })?;
 
-        self.cleanup()?;
-        //self.merge()?;
+        if let Some(current_id) = current_id {
+            self.cleanup(current_id)?;
+        }
+
+        Ok(())
+    }
+
+    /// recover_torn_write cuts off a partially written record at the end of a data file,
+    /// which is left behind if the process went down in the middle of a write. The
+    /// discarded bytes are moved to 'quarantine.<id>' so they can still be inspected.
+    /// The file gets its hint file right away, so it doesn't have to be read again.
+    fn recover_torn_write(&self, path: &Path) -> ErrorResult<()> {
+        let mut data_file = DataFile::create(path, true, self.inner.cipher.as_ref())?;
+        let (hints, torn_offset) = data_file.scan()?;
+
+        if let Some(torn_offset) = torn_offset {
+            let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+            let mut tail = Vec::new();
+            file.seek(SeekFrom::Start(torn_offset))?;
+            file.read_to_end(&mut tail)?;
+
+            let quarantine_path = self
+                .inner
+                .options
+                .base_dir
+                .join(format!("quarantine.{}", data_file.id));
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&quarantine_path)?
+                .write_all(&tail)?;
+
+            file.set_len(torn_offset)?;
+            file.sync_all()?;
+
+            warn!(
+                "Database.startup: discarded {} bytes of an incomplete record at offset {} of '{}' (moved to '{}')",
+                tail.len(),
+                torn_offset,
+                path.display(),
+                quarantine_path.display()
+            );
+        }
+
+        // files without records get no hint file, cleanup removes them:
+        if std::fs::metadata(path)?.len() > data_file.header.records_start() {
+            let base_dir = &self.inner.options.base_dir;
+            IndexFile::write_hints(base_dir, data_file.id, &hints, self.inner.cipher.as_ref())?;
+        }
 
         Ok(())
     }
 
-    /// call merge to reclaim some disk space
-    pub fn merge(&mut self) -> ErrorResult<()> {
-        let base_dir = &self.options.base_dir;
+    /// call merge to reclaim some disk space. It rewrites the immutable data files as of
+    /// its start, readers and writers keep going meanwhile and see the merged data file once
+    /// the keydir got switched over.
+    pub fn merge(&self) -> ErrorResult<MergeReport> {
+        self.merge_with(&CancellationToken::new(), |_| {})
+    }
+
+    /// merge_with merges like `merge`, calling `on_progress` as records get copied and once
+    /// a data file is done. Once `cancel` is cancelled the merge stops with Error::Cancelled and
+    /// removes what it has written so far, the store stays as it was. A merge which is about
+    /// to replace the data files is finished nevertheless.
+    pub fn merge_with(
+        &self,
+        cancel: &CancellationToken,
+        mut on_progress: impl FnMut(&MergeProgress),
+    ) -> ErrorResult<MergeReport> {
+        if self.inner.active.lock().unwrap().is_none() {
+            return Err(self.writer_gone());
+        }
 
-        let data_files: Vec<PathBuf> = self
-            .get_data_files_except_current(base_dir)?
+        let file_ids: Vec<u128> = self
+            .inner
+            .data_files
+            .read()
+            .unwrap()
             .iter()
-            .rev()
-            .cloned()
+            .map(|df| df.id)
             .collect();
-        trace!(
-            "merge: found Data Files before merge operation: {:?}",
-            data_files
-        );
 
-        if data_files.len() < 2 {
+        if file_ids.len() < 2 {
             // Nothing to merge, it does not make sense
-            return Ok(());
+            return Ok(MergeReport::default());
         }
 
-        // first removing all the startup indices:
-        let indices_paths = self.glob_files(&base_dir, "index.*")?;
-        for index_path in indices_paths {
-            let _ = std::fs::remove_file(&index_path);
+        self.merge_files_with(&file_ids, cancel, &mut on_progress)
+    }
+
+    /// merge_in_background runs `merge` on its own thread, the handle returns its result.
+    pub fn merge_in_background(&self) -> ErrorResult<JoinHandle<ErrorResult<MergeReport>>> {
+        let db = self.clone();
+        let handle = std::thread::Builder::new()
+            .name("bitcask-merge".to_owned())
+            .spawn(move || db.merge())?;
+
+        Ok(handle)
+    }
+
+    /// merge_incremental merges at most `max_files` immutable data files, the most
+    /// fragmented ones first. Other files and the rest of the keydir are left alone,
+    /// so it can be called regularly to merge a store step by step.
+    pub fn merge_incremental(&self, max_files: usize) -> ErrorResult<MergeReport> {
+        let mut data_files = self.inner.data_files.read().unwrap().clone();
+        data_files.sort_by(|a, b| b.fragmentation().total_cmp(&a.fragmentation()));
+
+        let file_ids: Vec<u128> = data_files.iter().take(max_files).map(|df| df.id).collect();
+        self.merge_files(&file_ids)
+    }
+
+    /// merge_files rewrites the live records of the given immutable data files into a
+    /// new data file and points their keys to it. Deletes and expired keys of these
+    /// files are only kept as tombstones while a file which isn't merged holds an older
+    /// record of their key, the other ones are purged.
+    ///
+    /// Only the immutable files are read, so writers and readers aren't blocked. Keys which
+    /// get written or removed while the merge runs keep their new record.
+    pub fn merge_files(&self, file_ids: &[u128]) -> ErrorResult<MergeReport> {
+        self.merge_files_with(file_ids, &CancellationToken::new(), &mut |_| {})
+    }
+
+    fn merge_files_with(
+        &self,
+        file_ids: &[u128],
+        cancel: &CancellationToken,
+        on_progress: &mut dyn FnMut(&MergeProgress),
+    ) -> ErrorResult<MergeReport> {
+        // one merge at a time, the files of a running one must not be merged again:
+        let _merging = self.inner.merge_lock.lock().unwrap();
+
+        let report = self.merge_locked(file_ids, cancel, on_progress);
+        *self.inner.merge_progress.lock().unwrap() = None;
+
+        // without a manifest the merge never happened and its output is removed. Otherwise
+        // the next startup finishes it. Only the writer, which holds the lock, cleans up:
+        if report.is_err() && self.inner.active.lock().unwrap().is_some() {
+            let base_dir = &self.inner.options.base_dir;
+            if MergeManifest::read(base_dir)?.is_none() {
+                roll_back_merges(base_dir, self.inner.options.unknown_files)?;
+            }
         }
 
+        report
+    }
+
+    /// merge_progress tells how far the running merge got, None if there is none.
+    pub fn merge_progress(&self) -> Option<MergeProgress> {
+        self.inner.merge_progress.lock().unwrap().clone()
+    }
+
+    fn merge_locked(
+        &self,
+        file_ids: &[u128],
+        cancel: &CancellationToken,
+        on_progress: &mut dyn FnMut(&MergeProgress),
+    ) -> ErrorResult<MergeReport> {
+        self.check_merge_cancelled(cancel)?;
+
+        let current_id = self
+            .inner
+            .active
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(|| self.writer_gone())?
+            .data_file
+            .id;
+
+        // the active file or ids which aren't (anymore) data files are skipped:
+        let (merged_files, unmerged_files): (Vec<DataFileMetadata>, Vec<DataFileMetadata>) = self
+            .inner
+            .data_files
+            .read()
+            .unwrap()
+            .iter()
+            .cloned()
+            .partition(|df| file_ids.contains(&df.id));
+        if merged_files.is_empty() {
+            return Ok(MergeReport::default());
+        }
+        let merged_ids: Vec<u128> = merged_files.iter().map(|df| df.id).collect();
+        trace!("merge_files: merging data files {:?}", merged_ids);
+
         let now = crate::utils::time();
-        let merged_path = base_dir.join(format!("merge.{}", now));
-        let mut temp_datastore = DataFile::create(&merged_path, false)?;
+        // newest delete or expiry per key and whether there was a delete record:
+        let mut tombstones: HashMap<Vec<u8>, (u128, bool)> = HashMap::new();
+        let mut num_deletes = 0;
+        let mut sources = HashMap::new();
+        for df in &merged_files {
+            let (_, hints) = self.load_hints(&df.path, Some(current_id))?;
+            for hint in hints {
+                let is_expired = hint.expires_at.is_some_and(|expires_at| expires_at <= now);
+                if hint.is_tombstone || is_expired {
+                    let tombstone = tombstones
+                        .entry(hint.key)
+                        .or_insert((hint.timestamp, false));
+                    tombstone.0 = hint.timestamp.max(tombstone.0);
+                    tombstone.1 |= hint.is_tombstone;
+                }
+                if hint.is_tombstone {
+                    num_deletes += 1;
+                }
+            }
 
-        let index_path = self.options.base_dir.join(format!("index.{}", now));
-        let mut index = IndexFile::create(&index_path, false)?;
+            let data_file = DataFile::create(&df.path, true, self.inner.cipher.as_ref())?;
+            sources.insert(df.id, data_file);
+        }
 
-        let keydir = &self.keydir;
+        let entries: Vec<(Vec<u8>, KeyDirEntry)> = {
+            let keydir = self.inner.keydir.read().unwrap();
+            // keys which are live again don't need their tombstone:
+            tombstones.retain(|key, _| keydir.get(key).is_err());
+
+            keydir
+                .iter()
+                .filter(|(_, entry)| merged_ids.contains(&entry.file_id))
+                .map(|(key, entry)| (key.clone(), *entry))
+                .collect()
+        };
+
+        // a tombstone is only needed as long as an unmerged file holds an older record of its
+        // key. Files which get rotated while the merge runs only hold newer records. Such a
+        // record is dead, so files without dead records don't need to be read:
+        if !tombstones.is_empty() {
+            let mut shadowing = HashSet::new();
+            for df in unmerged_files.iter().filter(|df| df.dead_keys > 0) {
+                let (_, hints) = self.load_hints(&df.path, Some(current_id))?;
+                for hint in hints {
+                    let is_older = tombstones
+                        .get(&hint.key)
+                        .is_some_and(|(timestamp, _)| hint.timestamp < *timestamp);
+                    if is_older && !hint.is_tombstone {
+                        shadowing.insert(hint.key);
+                    }
+                }
+            }
+            tombstones.retain(|key, _| shadowing.contains(key));
+        }
 
-        let mut num_entries_written = 0;
-        for (key, entry) in keydir.iter() {
-            let value = self.read(&key)?;
+        let num_kept_deletes = tombstones
+            .values()
+            .filter(|(_, is_delete)| *is_delete)
+            .count();
+        let mut report = MergeReport {
+            files_merged: merged_files.len() as u64,
+            tombstones_purged: (num_deletes - num_kept_deletes) as u64,
+            tombstones_kept: tombstones.len() as u64,
+            bytes_reclaimed: 0,
+        };
+
+        let base_dir = &self.inner.options.base_dir;
+        let merged_path = base_dir.join(crate::config::merge_file_format(now));
+        let mut merged = DataFile::create(&merged_path, false, self.inner.cipher.as_ref())?;
+
+        // a data file is done once its last live record got copied. What is left of it is
+        // the size of its records in the merged file:
+        let mut files: HashMap<u128, (u64, usize)> = merged_files
+            .iter()
+            .map(|df| (df.id, (df.total_bytes, 0)))
+            .collect();
+        for (_, entry) in &entries {
+            files.get_mut(&entry.file_id).unwrap().1 += 1;
+        }
 
-            // Keys that are in the 'mutable' datafile don't need to be
-            // written again, as it is just wasting time:
-            if self.current_data_file.id == entry.file_id {
-                continue;
+        let throttle = &self.inner.options.merge_throttle;
+        let mut run = MergeRun {
+            progress: MergeProgress {
+                bytes_processed: 0,
+                bytes_total: entries.iter().map(|(_, entry)| entry.size).sum(),
+                records_processed: 0,
+                records_total: (entries.len() + tombstones.len()) as u64,
+                files_done: 0,
+                files_total: merged_files.len() as u64,
+                keys_rewritten: 0,
+                bytes_reclaimed: 0,
+                started_at: Instant::now(),
+            },
+            throttle: Throttle::new(throttle.bytes_per_sec, throttle.records_per_sec),
+            cancel,
+            on_progress,
+        };
+        for (total_bytes, _) in files.values().filter(|(_, num_live)| *num_live == 0) {
+            run.progress.files_done += 1;
+            run.progress.bytes_reclaimed += total_bytes;
+        }
+        self.publish_merge_progress(&mut run);
+
+        let mut hints = Vec::with_capacity(entries.len() + tombstones.len());
+        let mut relocated = Vec::with_capacity(entries.len());
+        for (key, entry) in entries {
+            self.advance_merge(&mut run, entry.size)?;
+            let value = sources[&entry.file_id].read(entry.offset)?.value;
+
+            let record =
+                self.compress(Entry::put(&key, &value, entry.timestamp, entry.expires_at))?;
+            let (offset, size) = merged.append_records(&[record])?[0];
+            hints.push(IndexEntry {
+                key: key.clone(),
+                file_id: now,
+                offset,
+                timestamp: entry.timestamp,
+                value_size: value.len() as u64,
+                is_tombstone: false,
+                expires_at: entry.expires_at,
+                size,
+            });
+            relocated.push((key, entry, offset, size));
+            run.progress.keys_rewritten += 1;
+
+            let (left_bytes, num_live) = files.get_mut(&entry.file_id).unwrap();
+            *left_bytes = left_bytes.saturating_sub(size);
+            *num_live -= 1;
+            if *num_live == 0 {
+                run.progress.files_done += 1;
+                run.progress.bytes_reclaimed += *left_bytes;
+                self.publish_merge_progress(&mut run);
             }
+        }
+
+        let new_path = base_dir.join(crate::config::data_file_format(now));
+        let mut metadata = DataFileMetadata::new(now, new_path.clone());
+        for (key, (timestamp, _)) in tombstones {
+            self.advance_merge(&mut run, 0)?;
+            let record = Entry::new(EntryKind::Delete, &key, &[], timestamp);
+            let (offset, size) = merged.append_records(&[record])?[0];
+            run.progress.bytes_reclaimed = run.progress.bytes_reclaimed.saturating_sub(size);
+            hints.push(IndexEntry {
+                key,
+                file_id: now,
+                offset,
+                timestamp,
+                value_size: 0,
+                is_tombstone: true,
+                expires_at: None,
+                size,
+            });
+            metadata.add_dead(size, true);
+        }
 
-            let new_offset = temp_datastore.write(key, &value, entry.timestamp)?;
-            index.write(key, now, new_offset, entry.timestamp)?;
+        drop(sources);
+        self.publish_merge_progress(&mut run);
+        report.bytes_reclaimed = run.progress.bytes_reclaimed;
 
-            num_entries_written += 1;
+        // close waits for the merge, but a writer which is gone doesn't hold the lock of
+        // the directory anymore and must not replace any file:
+        self.check_merge_cancelled(cancel)?;
+        if self.inner.active.lock().unwrap().is_none() {
+            return Err(self.writer_gone());
         }
+        let manifest = self.commit_merge(merged, &hints, merged_ids.clone())?;
 
-        drop(temp_datastore);
-        drop(index);
+        {
+            let mut keydir = self.inner.keydir.write().unwrap();
+            let mut data_files = self.inner.data_files.write().unwrap();
 
-        if num_entries_written == 0 {
-            // The data_files only contains duplicate entries, which already exists in the
-            // "main keyfile" therefore lets delete these duplicates/old entries:
-            for path in data_files {
-                std::fs::remove_file(path)?;
+            // copies of keys which got written again meanwhile are dead right away:
+            for (key, entry, offset, size) in relocated {
+                if keydir.relocate(&key, &entry, now, offset, size) {
+                    metadata.add_live(size);
+                } else {
+                    metadata.add_dead(size, true);
+                }
             }
+            // expired keys weren't copied, their records are gone with the merged files:
+            keydir.remove_expired(&merged_ids, crate::utils::time());
 
-            self.data_files = Vec::new();
-            return Ok(());
+            data_files.retain(|df| !merged_ids.contains(&df.id));
+            if manifest.merged_id.is_some() {
+                data_files.push(metadata);
+            }
         }
 
-        let new_datafile_path = &base_dir.join(crate::config::data_file_format(now));
+        {
+            let mut cache = self.inner.data_files_cache.lock().unwrap();
+            for id in &merged_ids {
+                cache.pop(id);
+            }
+        }
+
+        manifest.finish(base_dir)?;
+
         trace!(
-            "trying to rename data file '{}' to '{}'",
-            merged_path.display(),
-            new_datafile_path.display()
+            "merge_files: merged data files {:?}: {:?}",
+            merged_ids,
+            report
         );
-        std::fs::rename(&merged_path, new_datafile_path)?;
+        Ok(report)
+    }
 
-        // glob all data files except for the ones we have merged. We cannot delete them yet because the keydir is not rebuilt yet:
-        let mut new_data_files: Vec<PathBuf> = self
-            .glob_files(&base_dir, crate::config::DATA_FILE_GLOB_FORMAT)?
-            .iter()
-            .cloned()
-            .filter(|item| !data_files.contains(&item))
-            .collect();
+    // accounts for a merged record in the progress and keeps the merge to its throttle:
+    fn advance_merge(&self, run: &mut MergeRun, bytes: u64) -> ErrorResult<()> {
+        self.check_merge_cancelled(run.cancel)?;
 
-        self.build_keydir(&mut new_data_files)?;
+        let cancel = run.cancel;
+        run.throttle.consume(bytes, || {
+            self.inner.closing.is_cancelled() || cancel.is_cancelled()
+        });
+        self.check_merge_cancelled(run.cancel)?;
 
-        for path in data_files {
-            std::fs::remove_file(path)?;
-        }
+        run.progress.bytes_processed += bytes;
+        run.progress.records_processed += 1;
+        self.publish_merge_progress(run);
 
         Ok(())
     }
 
-    fn get_data_files_except_current(&self, base_dir: &Path) -> ErrorResult<Vec<PathBuf>> {
-        let mut entries = self.glob_files(&base_dir, crate::config::DATA_FILE_GLOB_FORMAT)?;
+    fn check_merge_cancelled(&self, cancel: &CancellationToken) -> ErrorResult<()> {
+        if self.inner.closing.is_cancelled() {
+            Err(Error::Closed)
+        } else if cancel.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
 
-        entries.sort_by(|a, b| natord::compare(a.to_str().unwrap(), b.to_str().unwrap()));
+    fn publish_merge_progress(&self, run: &mut MergeRun) {
+        *self.inner.merge_progress.lock().unwrap() = Some(run.progress.clone());
+        (run.on_progress)(&run.progress);
+    }
+
+    /// commit_merge makes the merged data file and its hints durable and writes the
+    /// manifest which lets it replace the given files. A crash before the manifest is
+    /// written rolls the merge back, after it startup finishes the merge. The replaced
+    /// files have to be removed with `MergeManifest::finish` once the keydir got switched.
+    fn commit_merge(
+        &self,
+        mut merged: DataFile,
+        hints: &[IndexEntry],
+        replaced_ids: Vec<u128>,
+    ) -> ErrorResult<MergeManifest> {
+        let base_dir = &self.inner.options.base_dir;
+
+        merged.sync()?;
+        let merged_id = if hints.is_empty() {
+            None
+        } else {
+            IndexFile::write_hints(base_dir, merged.id, hints, self.inner.cipher.as_ref())?;
+            Some(merged.id)
+        };
+        crate::utils::sync_dir(base_dir)?;
+
+        let manifest = MergeManifest {
+            merged_id,
+            replaced_ids,
+        };
+        manifest.write(base_dir)?;
+        manifest.install(base_dir)?;
+
+        // renamed before it is dropped, without any records the merged file is removed on drop:
+        drop(merged);
+
+        Ok(manifest)
+    }
+
+    fn get_data_files_except_current(
+        &self,
+        base_dir: &Path,
+        current_id: Option<u128>,
+    ) -> ErrorResult<Vec<PathBuf>> {
+        let mut entries = self.glob_data_files(base_dir)?;
 
         // Remove current data file since the current data file is mutable:
-        entries.retain(|x| {
-            x.file_name().unwrap().to_str().unwrap()
-                != self
-                    .current_data_file
-                    .path
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-        });
+        if let Some(current_id) = current_id {
+            let current_name = crate::config::data_file_format(current_id);
+            entries.retain(|x| x.file_name() != Some(current_name.as_ref()));
+        }
 
         Ok(entries)
     }
 
-    fn glob_files(&self, base_dir: &Path, pattern: &'static str) -> ErrorResult<Vec<PathBuf>> {
-        let glob_path = base_dir.join(pattern);
-        let glob_result = glob(glob_path.to_str().unwrap())?;
-        let mut entries: Vec<PathBuf> = glob_result.map(|x| x.unwrap()).collect();
-        entries.sort_by(|a, b| natord::compare(a.to_str().unwrap(), b.to_str().unwrap()));
-        Ok(entries)
+    /// glob_data_files returns all 'data.<id>' files, sorted by id.
+    fn glob_data_files(&self, base_dir: &Path) -> ErrorResult<Vec<PathBuf>> {
+        glob_files_with_id(
+            base_dir,
+            crate::config::DATA_FILE_GLOB_FORMAT,
+            self.inner.options.unknown_files,
+        )
     }
 
-    fn build_keydir(&mut self, datafiles_paths: &mut Vec<PathBuf>) -> ErrorResult<()> {
+    fn build_keydir(
+        &self,
+        current_id: Option<u128>,
+        datafiles_paths: &mut Vec<PathBuf>,
+    ) -> ErrorResult<()> {
         trace!(
             "rebuilding keydir now based on the files: {:?}",
             datafiles_paths
         );
 
-        let base_dir = self.options.base_dir.to_owned();
-
-        // take ownership of these
-        let keydir = Arc::new(Mutex::new(KeyDir::new()));
-        let data_files = Arc::new(Mutex::new(Vec::new()));
-
         trace!("Database.build_keydir: Starting to rebuild keydir now...");
-        datafiles_paths.par_iter_mut().for_each({
-            let keydir = Arc::clone(&keydir);
-            let data_files = Arc::clone(&data_files);
+        let loaded = datafiles_paths
+            .par_iter()
+            .map(|entry| {
+                self.load_hints(entry, current_id)
+                    .map_err(|source| Error::FileLoad {
+                        path: entry.to_path_buf(),
+                        source: Box::new(source),
+                    })
+            })
+            .collect::<ErrorResult<Vec<_>>>()?;
 
-            move |entry| {
-                let mut counter = 0;
-
-                let file_id = crate::utils::extract_id_from_filename(&entry).unwrap();
-
-                let index_path = base_dir.join(format!("index.{}", file_id));
-                trace!("Database.build_keydir: check if index exist '{}'", index_path.display());
-
-                if index_path.exists() {
-                    trace!("Database.build_keydir: index found 'index.{}'. Importing data file No={} Path={} ...", file_id, file_id, &entry.display());
-
-                    let mut index = IndexFile::create(&index_path, true).unwrap();
-
-                    for (_, entry) in index.iter() {
-                        {
-                            let mut keydir = keydir.lock().unwrap();
-                            let set_result = keydir.set(&entry.key, entry.file_id, entry.offset, entry.timestamp);
-                            if let Err(err) = set_result {
-                                trace!("Setting value into keydir has failed: {}", err);
-                            }
-                        }
+        trace!("Database.build_keydir: Finished rebuilding keydir ...");
 
-                        counter += 1;
-                    }
+        // newest record per key (put or delete), independent of the order the files were read in:
+        let mut latest = HashMap::<Vec<u8>, IndexEntry>::new();
+        for (_, hints) in &loaded {
+            for hint in hints {
+                let is_newer = latest.get(&hint.key).is_none_or(|current| {
+                    (hint.timestamp, hint.file_id, hint.offset)
+                        > (current.timestamp, current.file_id, current.offset)
+                });
+
+                if is_newer {
+                    latest.insert(hint.key.clone(), hint.clone());
+                }
+            }
+        }
 
-                    trace!("Database.build_keydir: index 'index.{}' fully read. Imported index file No={} Path={} NumRecords={}", file_id, file_id, entry.display(), counter);
+        // keys whose newest record is a delete or which expired are gone:
+        let now = crate::utils::time();
+        let mut keydir = KeyDir::new();
+        for (key, hint) in latest {
+            let is_expired = hint.expires_at.is_some_and(|expires_at| expires_at <= now);
+            if !hint.is_tombstone && !is_expired {
+                keydir.set(
+                    &key,
+                    hint.file_id,
+                    hint.offset,
+                    hint.timestamp,
+                    hint.expires_at,
+                    hint.size,
+                )?;
+            }
+        }
 
+        // every record which isn't the one the keydir points to is dead:
+        let mut data_files = Vec::with_capacity(loaded.len());
+        for (mut data_file, hints) in loaded {
+            for hint in hints {
+                let is_live = keydir.get(&hint.key).is_ok_and(|entry| {
+                    entry.file_id == data_file.id && entry.offset == hint.offset
+                });
+                if is_live {
+                    data_file.live_keys += 1;
+                    data_file.live_bytes += hint.size;
                 } else {
-                    trace!("Database.build_keydir: start loading datafile No={} Path={} NumRecords={}", file_id, entry.display(), counter);
-                    let mut df = DataFile::create(&entry, true).unwrap();
+                    data_file.dead_keys += 1;
+                }
+            }
+            data_files.push(data_file);
+        }
 
-                    for (offset, record) in df.iter() {
-                        let mut keydir = keydir.lock().unwrap();
+        // Removing the current file as the current one is not an immutable data file yet:
+        data_files.retain(|df| Some(df.id) != current_id);
 
-                        if record.value == crate::config::REMOVE_TOMBSTONE {
-                            trace!("Database.build_keydir: loading datafile No={} Path={} NumRecords={}: Removing key", file_id, entry.display(), counter);
-                            keydir.remove(&record.key).unwrap_or_default();
-                            continue;
-                        }
+        {
+            let mut current_keydir = self.inner.keydir.write().unwrap();
+            let mut current_data_files = self.inner.data_files.write().unwrap();
 
-                        {
-                            let maybe_current_entry = keydir.get(&record.key);
-
-                            if let Ok(current_entry) = maybe_current_entry {
-                                if record.timestamp > current_entry.timestamp {
-                                    keydir.set(record.key.as_slice(), file_id, offset, record.timestamp).unwrap();
-                                }
-                            } else {
-                                keydir.set(record.key.as_slice(), file_id, offset, record.timestamp).unwrap();
-                            }
-                        }
+            trace!(
+                "Assigning new data files to internal struct: {:?} => {:?}",
+                current_data_files,
+                data_files
+            );
+            *current_data_files = data_files;
+            *current_keydir = keydir;
+        }
 
-                        counter += 1;
-                    }
+        if let Some(current_id) = current_id {
+            self.cleanup(current_id)?;
+        }
 
-                    trace!("Database.build_keydir: loading datafile No={} Path={} NumRecords={}", file_id, entry.display(), counter);
-                }
+        Ok(())
+    }
 
+    /// load_hints returns the hints of a data file, read from its hint file if there is one.
+    fn load_hints(
+        &self,
+        path: &Path,
+        current_id: Option<u128>,
+    ) -> ErrorResult<(DataFileMetadata, Vec<IndexEntry>)> {
+        let base_dir = &self.inner.options.base_dir;
+        let read_only = self.inner.options.read_only;
+        let file_id = crate::utils::extract_id_from_filename(path)?;
+
+        // opening the data file checks its header, files of an unknown version are refused
+        // even if their hint file is all that gets read:
+        let mut data_file = DataFile::create(path, true, self.inner.cipher.as_ref())?;
+
+        let index_path = base_dir.join(crate::config::index_file_format(file_id));
+        trace!(
+            "Database.load_hints: check if index exist '{}'",
+            index_path.display()
+        );
 
-                let mut data_files = data_files.lock().unwrap();
-                data_files.push(DataFileMetadata {
-                    id: file_id,
-                    path: entry.to_path_buf(),
-                })
-            }
-        });
+        let mut hints = if index_path.exists() {
+            trace!("Database.load_hints: index found 'index.{}'. Importing data file No={} Path={} ...", file_id, file_id, path.display());
+
+            let index = IndexFile::create(&index_path, true, self.inner.cipher.as_ref())?;
+            let hints = index
+                .iter()?
+                .map(|item| item.map(|(_, hint)| hint))
+                .collect::<ErrorResult<Vec<_>>>();
+            hints.map_err(|source| Error::FileLoad {
+                path: index_path.clone(),
+                source: Box::new(source),
+            })?
+        } else {
+            trace!(
+                "Database.load_hints: no index found, start loading datafile No={} Path={}",
+                file_id,
+                path.display()
+            );
 
-        trace!("Database.build_keydir: Finished rebuilding keydir ...");
+            // a corrupted record aborts the startup instead of loading corrupted data. Files
+            // without hints of a read-only database can be the active file of the writer,
+            // which can be in the middle of a write:
+            let hints = data_file.read_hints(read_only)?;
 
-        // take ownership of the arc wrapped value
-        // this shouldn't fail because rayon blocks until its done
-        // try_unwrap gets the value out of the Arc if there aren't any extra refs to it
-        let mut data_files = Arc::try_unwrap(data_files)
-            .ok() // ignore error value
-            // get the value out of the mutex
-            // into_inner gets the value out of the mutex if its not locked
-            .and_then(|mutex| mutex.into_inner().ok())
-            .expect("rayon to finish");
-
-        let keydir = Arc::try_unwrap(keydir)
-            .ok() // ignore error value
-            // get the value out of the mutex
-            .and_then(|mutex| mutex.into_inner().ok())
-            .expect("rayon to finish");
+            // the active data file is still growing, it gets its hint file once it is rotated.
+            // Files without records get none, cleanup removes them:
+            let has_records = std::fs::metadata(path)?.len() > data_file.header.records_start();
+            if Some(file_id) != current_id && !read_only && has_records {
+                IndexFile::write_hints(base_dir, file_id, &hints, self.inner.cipher.as_ref())?;
+            }
 
-        // Removing the current file as the current one is not an immutable data file yet:
-        data_files.retain(|df| df.id != self.current_data_file.id);
+            hints
+        };
 
         trace!(
-            "Assigning new data files to internal struct: {:?} => {:?}",
-            self.data_files,
-            data_files
+            "Database.load_hints: loaded data file No={} Path={} NumRecords={}",
+            file_id,
+            path.display(),
+            hints.len()
         );
-        self.data_files = data_files;
-        self.keydir = keydir;
 
-        self.cleanup()?;
+        // records follow each other, so their size is the distance to the next one.
+        // Batch markers have no hint, they count towards the record before them:
+        let file_len = std::fs::metadata(path)?.len();
+        let mut offsets: Vec<u64> = hints.iter().map(|hint| hint.offset).collect();
+        offsets.sort_unstable();
+        for hint in &mut hints {
+            let next = offsets.partition_point(|&offset| offset <= hint.offset);
+            hint.size = offsets.get(next).copied().unwrap_or(file_len) - hint.offset;
+        }
 
-        Ok(())
+        let data_file = DataFileMetadata {
+            total_bytes: file_len.saturating_sub(data_file.header.records_start()),
+            ..DataFileMetadata::new(file_id, path.to_path_buf())
+        };
+        Ok((data_file, hints))
     }
 
-    fn cleanup(&mut self) -> ErrorResult<()> {
-        let entries =
-            self.glob_files(&self.options.base_dir, crate::config::DATA_FILE_GLOB_FORMAT)?;
+    fn cleanup(&self, current_id: u128) -> ErrorResult<()> {
+        // leftovers of hint files which were not completely written:
+        let tmp_indices = glob_files_with_id(
+            &self.inner.options.base_dir,
+            crate::config::INDEX_TMP_FILE_GLOB_FORMAT,
+            self.inner.options.unknown_files,
+        )?;
+        for tmp_index in tmp_indices {
+            trace!("... removing incomplete hint file {}", tmp_index.display());
+            let _ = std::fs::remove_file(&tmp_index);
+        }
+
+        // leftovers of an interrupted key rotation, the data file itself is still complete:
+        let rekeyed = glob_files_with_id(
+            &self.inner.options.base_dir,
+            crate::config::REKEY_FILE_GLOB_FORMAT,
+            self.inner.options.unknown_files,
+        )?;
+        for rekeyed in rekeyed {
+            trace!("... removing incomplete rekeyed file {}", rekeyed.display());
+            let _ = std::fs::remove_file(&rekeyed);
+        }
+
+        let entries = self.glob_data_files(&self.inner.options.base_dir)?;
 
         for entry in entries {
             let file_id = crate::utils::extract_id_from_filename(&entry)?;
-            if self.current_data_file.get_id() == file_id {
+            if current_id == file_id {
                 continue;
             }
 
-            // cleaning up old files with 0 bytes size:
+            // cleaning up old files without any records:
             let info = std::fs::metadata(&entry)?;
-            if info.len() == 0 && std::fs::remove_file(&entry).is_ok() {
+            let records_start = DataFile::create(&entry, true, self.inner.cipher.as_ref())?
+                .header
+                .records_start();
+            if info.len() <= records_start && std::fs::remove_file(&entry).is_ok() {
                 trace!(
-                    "... removing {} since it is zero bytes and its not the current data file id (this: {}, current: {})",
+                    "... removing {} since it holds no records and its not the current data file id (this: {}, current: {})",
                     entry.display(),
                     file_id,
-                    self.current_data_file.get_id()
+                    current_id
                 );
             }
         }
@@ -373,11 +1466,11 @@ impl Database {
         Ok(())
     }
 
-    fn switch_to_new_data_file(&mut self) -> ErrorResult<()> {
+    fn switch_to_new_data_file(&self, active: &mut ActiveDataFile) -> ErrorResult<()> {
+        let base_dir = &self.inner.options.base_dir;
         let data_file_id = crate::utils::time();
 
-        let new_path = std::path::Path::new(&self.options.base_dir)
-            .join(crate::config::data_file_format(data_file_id));
+        let new_path = base_dir.join(crate::config::data_file_format(data_file_id));
 
         trace!(
             "Database.switch_to_new_data_file: New data file is {} (file_id={})",
@@ -385,54 +1478,271 @@ impl Database {
             data_file_id
         );
 
-        let new_data_file = DataFile::create(new_path.as_path(), false)?;
-        let old_data_file = std::mem::replace(&mut self.current_data_file, new_data_file);
+        let new_data_file =
+            DataFile::create(new_path.as_path(), false, self.inner.cipher.as_ref())?;
+        let new_metadata = DataFileMetadata::new(new_data_file.id, new_data_file.path.clone());
+        let mut old_data_file = std::mem::replace(&mut active.data_file, new_data_file);
+        let old_metadata = std::mem::replace(&mut active.metadata, new_metadata);
+        old_data_file.sync()?;
 
-        let data_file_id = self.current_data_file.get_id();
         trace!(
             "Database.switch_to_new_data_file: Switched data file. Old_Id={} New_Id={}",
             old_data_file.get_id(),
-            data_file_id
+            active.data_file.get_id()
         );
 
-        self.data_files.push(DataFileMetadata {
-            id: old_data_file.id,
-            path: std::path::Path::new(&self.options.base_dir)
-                .join(crate::config::data_file_format(old_data_file.id)),
-        });
+        // the old data file is immutable from now on, so its hint file can be written:
+        let hints = std::mem::take(&mut active.hints);
+        IndexFile::write_hints(
+            base_dir,
+            old_data_file.id,
+            &hints,
+            self.inner.cipher.as_ref(),
+        )?;
+
+        self.inner.data_files.write().unwrap().push(old_metadata);
 
         Ok(())
     }
 
-    pub fn write(&mut self, key: &[u8], value: &[u8]) -> ErrorResult<()> {
-        let data_file_id = self.current_data_file.get_id();
+    pub fn write(&self, key: &[u8], value: &[u8]) -> ErrorResult<()> {
+        self.commit(WriteRequest {
+            ops: vec![BatchOp::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            }],
+            is_batch: false,
+            expires_at: None,
+            expected: None,
+        })
+        .map(|_| ())
+    }
+
+    /// write_with_ttl stores a key which expires after `ttl`. Expired keys are treated
+    /// as not found and are dropped by the next merge.
+    pub fn write_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> ErrorResult<()> {
+        self.commit(WriteRequest {
+            ops: vec![BatchOp::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            }],
+            is_batch: false,
+            expires_at: Some(crate::utils::time() + ttl.as_nanos()),
+            expected: None,
+        })
+        .map(|_| ())
+    }
+
+    /// compare_and_swap sets `key` to `new`, or removes it with None, if its current value is
+    /// `expected` (None: the key does not exist). The check happens right before the write,
+    /// no other write can get in between. Returns whether the swap happened.
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> ErrorResult<bool> {
+        let op = match new {
+            Some(value) => BatchOp::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+            None => BatchOp::Delete { key: key.to_vec() },
+        };
+
+        self.commit(WriteRequest {
+            ops: vec![op],
+            is_batch: false,
+            expires_at: None,
+            expected: Some(expected.map(<[u8]>::to_vec)),
+        })
+    }
+
+    /// put_if_absent writes the key unless it exists. Returns whether it got written.
+    pub fn put_if_absent(&self, key: &[u8], value: &[u8]) -> ErrorResult<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// commit queues the write for the next group commit and returns once it is written
+    /// (and synced, depending on the sync policy). Returns whether the write got applied,
+    /// only compare and swaps can be skipped.
+    fn commit(&self, request: WriteRequest) -> ErrorResult<bool> {
+        self.inner
+            .commit_queue
+            .commit(request, |requests| self.write_group(requests))?
+    }
+
+    /// write_group appends the records of all requests with a single write and one sync.
+    fn write_group(&self, requests: &[&WriteRequest]) -> ErrorResult<Vec<ErrorResult<bool>>> {
+        let mut active = self.inner.active.lock().unwrap();
+        let active = active.as_mut().ok_or_else(|| self.writer_gone())?;
+        let data_file_id = active.data_file.get_id();
+
+        // compare and swaps which don't hold are left out:
+        let applied = self.check_expected(requests);
+        let requests: Vec<&WriteRequest> = requests
+            .iter()
+            .zip(&applied)
+            .filter(|(_, applied)| matches!(applied, Ok(true)))
+            .map(|(request, _)| *request)
+            .collect();
+        if requests.is_empty() {
+            return Ok(applied);
+        }
+
+        // every request gets its own timestamp, the records of a batch share theirs:
+        let mut entries = Vec::new();
+        let mut timestamps = Vec::with_capacity(requests.len());
+        for request in &requests {
+            let timestamp = crate::utils::time();
+            if request.is_batch {
+                entries.push(Entry::batch_begin(request.ops.len() as u64, timestamp));
+            }
+            for op in &request.ops {
+                entries.push(self.compress(Entry::from_op(op, timestamp, request.expires_at))?);
+            }
+            timestamps.push(timestamp);
+        }
 
-        let timestamp = crate::utils::time();
+        let records = active.data_file.append_records(&entries)?;
+        self.apply_sync_policy(active)?;
 
-        let offset = self.current_data_file.write(key, value, timestamp)?;
-        self.keydir.set(&key, data_file_id, offset, timestamp)?;
+        let last_offset = records[records.len() - 1].0;
+        let mut records = records.into_iter();
 
-        if offset >= self.data_file_limit {
+        {
+            // readers see either none or all of a batch:
+            let mut keydir = self.inner.keydir.write().unwrap();
+            let mut data_files = self.inner.data_files.write().unwrap();
+
+            for (request, &timestamp) in requests.iter().zip(timestamps.iter()) {
+                if request.is_batch {
+                    // skipping the batch marker:
+                    if let Some((_, size)) = records.next() {
+                        active.metadata.add_dead(size, false);
+                    }
+                }
+
+                for (op, (offset, size)) in request.ops.iter().zip(&mut records) {
+                    let (value_size, expires_at, replaced) = match op {
+                        BatchOp::Put { key, value } => {
+                            let expires_at = request.expires_at;
+                            let replaced = keydir.set(
+                                key,
+                                data_file_id,
+                                offset,
+                                timestamp,
+                                expires_at,
+                                size,
+                            )?;
+                            active.metadata.add_live(size);
+                            (value.len() as u64, expires_at, replaced)
+                        }
+                        BatchOp::Delete { key } => {
+                            active.metadata.add_dead(size, true);
+                            (0, None, keydir.remove(key)?)
+                        }
+                    };
+
+                    // the record the key pointed to before is dead now:
+                    if let Some(replaced) = replaced {
+                        if replaced.file_id == data_file_id {
+                            active.metadata.kill(replaced.size);
+                        } else if let Some(data_file) =
+                            data_files.iter_mut().find(|df| df.id == replaced.file_id)
+                        {
+                            data_file.kill(replaced.size);
+                        }
+                    }
+
+                    active.hints.push(IndexEntry {
+                        key: op.key().to_vec(),
+                        file_id: data_file_id,
+                        offset,
+                        timestamp,
+                        value_size,
+                        is_tombstone: matches!(op, BatchOp::Delete { .. }),
+                        expires_at,
+                        size,
+                    });
+                }
+            }
+        }
+
+        // plain removes never start a new data file, puts and batches do.
+        // The file header does not count towards the data file limit:
+        let may_switch = requests
+            .iter()
+            .any(|request| request.is_batch || matches!(request.ops[0], BatchOp::Put { .. }));
+        if may_switch && last_offset - FileHeader::SIZE >= self.inner.data_file_limit {
             trace!(
-                "Database.write: Offset threshold reached for data file id '{}', key '{}':  {} < {}. Switching to new data file",
+                "Database.write_group: Offset threshold reached for data file id '{}': {} >= {}. Switching to new data file",
                 data_file_id,
-                std::str::from_utf8(&key)?,
-                offset,
-                self.data_file_limit
+                last_offset,
+                self.inner.data_file_limit
             );
-            return self.switch_to_new_data_file();
+            self.switch_to_new_data_file(active)?;
         }
 
-        Ok(())
+        Ok(applied)
+    }
+
+    /// check_expected tells per request whether it gets applied. Compare and swaps see
+    /// the values written by the requests before them in the group.
+    fn check_expected(&self, requests: &[&WriteRequest]) -> Vec<ErrorResult<bool>> {
+        let mut written: HashMap<&[u8], Option<&[u8]>> = HashMap::new();
+        let mut applied = Vec::with_capacity(requests.len());
+
+        for request in requests {
+            let is_applied = match &request.expected {
+                None => Ok(true),
+                Some(expected) => {
+                    let key = request.ops[0].key();
+                    match written.get(key) {
+                        Some(value) => Ok(*value == expected.as_deref()),
+                        None => self.current_value(key).map(|value| value == *expected),
+                    }
+                }
+            };
+
+            if matches!(is_applied, Ok(true)) {
+                for op in &request.ops {
+                    let value = match op {
+                        BatchOp::Put { value, .. } => Some(value.as_slice()),
+                        BatchOp::Delete { .. } => None,
+                    };
+                    written.insert(op.key(), value);
+                }
+            }
+            applied.push(is_applied);
+        }
+
+        applied
+    }
+
+    // current_value is like read, but a missing key is None:
+    fn current_value(&self, key: &[u8]) -> ErrorResult<Option<Vec<u8>>> {
+        let keydir = self.inner.keydir.read().unwrap();
+        match keydir.get(key) {
+            Ok(entry) => self.read_entry(&entry).map(Some),
+            Err(Error::NotFound { .. }) => Ok(None),
+            Err(err) => Err(err),
+        }
     }
 
     pub fn read(&self, key: &[u8]) -> ErrorResult<Vec<u8>> {
-        let entry = self.keydir.get(key)?;
+        // the keydir stays locked while reading, so merge can't delete the file underneath:
+        let keydir = self.inner.keydir.read().unwrap();
+        let entry = keydir.get(key)?;
+
+        self.read_entry(&entry)
+    }
 
+    fn read_entry(&self, entry: &KeyDirEntry) -> ErrorResult<Vec<u8>> {
         let data_filename = crate::config::data_file_format(entry.file_id);
-        let path = std::path::Path::new(&self.options.base_dir).join(data_filename);
+        let path = self.inner.options.base_dir.join(data_filename);
 
-        let mut data_file = DataFile::create(&path, true)?;
+        let data_file = DataFile::create(&path, true, self.inner.cipher.as_ref())?;
         trace!(
             "Database.read: Trying to read from offset {} from file {}",
             entry.offset,
@@ -443,70 +1753,241 @@ impl Database {
         Ok(found_entry.value)
     }
 
-    pub fn read_cache(&mut self, key: &[u8]) -> ErrorResult<Vec<u8>> {
-        let entry = self.keydir.get(key)?;
-
-        if let Some(df) = self.data_files_cache.get_mut(&entry.file_id) {
+    pub fn read_cache(&self, key: &[u8]) -> ErrorResult<Vec<u8>> {
+        let keydir = self.inner.keydir.read().unwrap();
+        let entry = keydir.get(key)?;
+
+        // the cache is only locked for the lookup, reading happens on a shared handle:
+        let cached = self
+            .inner
+            .data_files_cache
+            .lock()
+            .unwrap()
+            .get(&entry.file_id)
+            .cloned();
+        if let Some(df) = cached {
             let found_entry = df.read(entry.offset)?;
             return Ok(found_entry.value);
         }
 
         let data_filename = crate::config::data_file_format(entry.file_id);
-        let path = std::path::Path::new(&self.options.base_dir).join(data_filename);
+        let path = self.inner.options.base_dir.join(data_filename);
 
-        let mut data_file = DataFile::create(&path, true)?;
+        let data_file = DataFile::create(&path, true, self.inner.cipher.as_ref())?;
         trace!(
             "Database.read: Trying to read from offset {} from file {}",
             entry.offset,
             path.display()
         );
         let found_entry = data_file.read(entry.offset)?;
-        let _ = self.data_files_cache.put(entry.file_id, data_file);
+        let _ = self
+            .inner
+            .data_files_cache
+            .lock()
+            .unwrap()
+            .put(entry.file_id, Arc::new(data_file));
         Ok(found_entry.value)
     }
 
-    pub fn remove(&mut self, key: &[u8]) -> ErrorResult<()> {
-        let timestamp = crate::utils::time();
-        self.current_data_file.remove(key, timestamp)?;
-        self.keydir.remove(key)
+    pub fn remove(&self, key: &[u8]) -> ErrorResult<()> {
+        self.commit(WriteRequest {
+            ops: vec![BatchOp::Delete { key: key.to_vec() }],
+            is_batch: false,
+            expires_at: None,
+            expected: None,
+        })
+        .map(|_| ())
+    }
+
+    /// write_batch appends all puts and removes of the batch as one unit. If the write
+    /// gets interrupted, none of them will be visible after the next startup.
+    pub fn write_batch(&self, batch: &WriteBatch) -> ErrorResult<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.commit(WriteRequest {
+            ops: batch.ops().to_vec(),
+            is_batch: true,
+            expires_at: None,
+            expected: None,
+        })
+        .map(|_| ())
     }
 
     // get_datafile_at should only be used for debugging:
-    pub fn get_datafile_at(&mut self, index: u32) -> DataFile {
-        let df = self.data_files.get_mut(index as usize).unwrap();
-        DataFile::create(&df.path, true).unwrap()
+    pub fn get_datafile_at(&self, index: u32) -> ErrorResult<DataFile> {
+        let data_files = self.inner.data_files.read().unwrap();
+        let df = data_files.get(index as usize).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no immutable data file at index {}", index),
+            )
+        })?;
+        DataFile::create(&df.path, true, self.inner.cipher.as_ref())
     }
 
-    pub fn get_current_datafile(&mut self) -> DataFile {
-        let path = self.current_data_file.path.as_path();
-        DataFile::create(&path, true).unwrap()
+    pub fn get_current_datafile(&self) -> ErrorResult<DataFile> {
+        let active = self.inner.active.lock().unwrap();
+        let active = active.as_ref().ok_or_else(|| self.writer_gone())?;
+        DataFile::create(&active.data_file.path, true, self.inner.cipher.as_ref())
     }
 
-    pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
-        self.keydir.keys()
+    // The keys are copied since the keydir can't stay locked while the caller iterates:
+    pub fn keys(&self) -> impl Iterator<Item = Vec<u8>> {
+        let keydir = self.inner.keydir.read().unwrap();
+        keydir.keys().cloned().collect::<Vec<_>>().into_iter()
     }
 
     pub fn keys_range(
         &self,
         min: &[u8],
         max: &[u8],
-    ) -> impl Iterator<Item = (&Vec<u8>, &KeyDirEntry)> {
-        self.keydir.keys_range(min, max)
+    ) -> impl Iterator<Item = (Vec<u8>, KeyDirEntry)> {
+        let keydir = self.inner.keydir.read().unwrap();
+        to_owned_entries(keydir.keys_range(min, max))
+    }
+
+    pub fn keys_range_min(&self, min: &[u8]) -> impl Iterator<Item = (Vec<u8>, KeyDirEntry)> {
+        let keydir = self.inner.keydir.read().unwrap();
+        to_owned_entries(keydir.keys_range_min(min))
     }
 
-    pub fn keys_range_min(&self, min: &[u8]) -> impl Iterator<Item = (&Vec<u8>, &KeyDirEntry)> {
-        self.keydir.keys_range_min(min)
+    pub fn keys_range_max(&self, max: &[u8]) -> impl Iterator<Item = (Vec<u8>, KeyDirEntry)> {
+        let keydir = self.inner.keydir.read().unwrap();
+        to_owned_entries(keydir.keys_range_max(max))
     }
 
-    pub fn keys_range_max(&self, max: &[u8]) -> impl Iterator<Item = (&Vec<u8>, &KeyDirEntry)> {
-        self.keydir.keys_range_max(max)
+    pub fn sync(&self) -> ErrorResult<()> {
+        let mut active = self.inner.active.lock().unwrap();
+        match active.as_mut() {
+            Some(active) => active.data_file.sync(),
+            // nothing was written:
+            None if self.inner.options.read_only => Ok(()),
+            None => Err(Error::Closed),
+        }
+    }
+
+    fn compress(&self, entry: Entry) -> ErrorResult<Entry> {
+        let (codec, level) = match self.inner.options.compression {
+            Compression::None => return Ok(entry),
+            Compression::Lz4 => (Codec::Lz4, 0),
+            Compression::Zstd(level) => (Codec::Zstd, level),
+        };
+
+        entry.compress(codec, level, self.inner.options.compression_min_size)
+    }
+
+    fn apply_sync_policy(&self, active: &mut ActiveDataFile) -> ErrorResult<()> {
+        match self.inner.options.sync_policy {
+            SyncPolicy::Always => active.data_file.sync_data(),
+            SyncPolicy::Bytes(bytes) if active.data_file.unsynced_bytes() >= bytes => {
+                self.inner.sync_signal.request();
+                Ok(())
+            }
+            _ => Ok(()),
+        }
     }
 
-    pub fn sync(&mut self) -> ErrorResult<()> {
-        self.current_data_file.sync()
+    // called by the background sync thread:
+    fn sync_unsynced(&self) -> ErrorResult<()> {
+        let mut active = self.inner.active.lock().unwrap();
+
+        match active.as_mut() {
+            Some(active) if active.data_file.unsynced_bytes() > 0 => active.data_file.sync_data(),
+            _ => Ok(()),
+        }
     }
 
-    pub fn close(&mut self) -> ErrorResult<()> {
-        self.sync()
+    // writes are rejected if there is no active data file:
+    fn writer_gone(&self) -> Error {
+        if self.inner.options.read_only {
+            Error::ReadOnly
+        } else {
+            Error::Closed
+        }
     }
+
+    /// pause_merges stops the merge policy from starting merges until resume_merges
+    /// is called. A merge which is already running is not interrupted.
+    pub fn pause_merges(&self) {
+        self.inner.merges_paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume_merges(&self) {
+        self.inner.merges_paused.store(false, Ordering::SeqCst);
+        self.inner.merge_signal.request();
+    }
+
+    /// close flushes all pending writes and releases the lock of the database
+    /// directory. A running merge is stopped and waited for first. Reads keep working,
+    /// writes fail with `Error::Closed`.
+    pub fn close(&self) -> ErrorResult<()> {
+        self.inner.closing.cancel();
+        let _merging = self.inner.merge_lock.lock().unwrap();
+        let mut active = self.inner.active.lock().unwrap();
+
+        if let Some(mut active) = active.take() {
+            active.data_file.sync()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn to_owned_entries<'a>(
+    entries: impl Iterator<Item = (&'a Vec<u8>, &'a KeyDirEntry)>,
+) -> std::vec::IntoIter<(Vec<u8>, KeyDirEntry)> {
+    entries
+        .map(|(key, entry)| (key.clone(), *entry))
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+fn glob_files(base_dir: &Path, pattern: &'static str) -> ErrorResult<Vec<PathBuf>> {
+    let glob_path = base_dir.join(pattern);
+    let pattern = glob_path.to_str().ok_or_else(|| Error::InvalidPath {
+        path: glob_path.clone(),
+    })?;
+    let glob_result = glob(pattern).map_err(|source| Error::InvalidPattern {
+        pattern: pattern.to_owned(),
+        source: Arc::new(source),
+    })?;
+
+    let mut entries = Vec::new();
+    for entry in glob_result {
+        let entry = entry.map_err(|err| Error::FileLoad {
+            path: err.path().to_path_buf(),
+            source: Box::new(std::io::Error::from(err).into()),
+        })?;
+        entries.push(entry);
+    }
+
+    entries.sort_by(|a, b| natord::compare(&a.to_string_lossy(), &b.to_string_lossy()));
+    Ok(entries)
+}
+
+/// glob_files_with_id returns all '<name>.<id>' files of the pattern. Files which only
+/// happen to match the pattern (ie. 'data.foo') are not ours and handled per the
+/// unknown_files policy:
+fn glob_files_with_id(
+    base_dir: &Path,
+    pattern: &'static str,
+    unknown_files: UnknownFilePolicy,
+) -> ErrorResult<Vec<PathBuf>> {
+    let mut entries = Vec::new();
+
+    for entry in glob_files(base_dir, pattern)? {
+        match crate::utils::extract_id_from_filename(&entry) {
+            Ok(_) => entries.push(entry),
+            Err(err) if unknown_files == UnknownFilePolicy::Fail => return Err(err),
+            Err(_) => warn!(
+                "Database: ignoring '{}' since it is not in format {}",
+                entry.display(),
+                pattern.replace('*', "<id>")
+            ),
+        }
+    }
+
+    Ok(entries)
 }
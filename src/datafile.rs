@@ -4,21 +4,84 @@ use std::io::SeekFrom;
 
 use serde::{Deserialize, Serialize};
 
+use crate::batch::BatchOp;
+use crate::cipher::Cipher;
 use crate::config::REMOVE_TOMBSTONE;
+use crate::error::Error;
+use crate::header::{FileHeader, DATA_FILE_MAGIC, DATA_FILE_VERSION, ENCRYPTED_DATA_FILE_MAGIC};
+use crate::indexfile::IndexEntry;
 use crate::*;
 
-#[derive(Clone, Debug)]
+// how far after a record which runs into the end of the file a valid record is looked for:
+const RESYNC_WINDOW: usize = 64 * 1024;
+
+/// DataFileMetadata describes a data file and how much of it is still in use.
+/// Records of keys which got overwritten, removed or expired are dead.
+#[derive(Clone, Debug, Default)]
 pub struct DataFileMetadata {
     pub id: u128,
     pub path: std::path::PathBuf,
+
+    pub live_keys: u64,
+    pub dead_keys: u64,
+    pub live_bytes: u64,
+    // all records, without the file header:
+    pub total_bytes: u64,
+}
+
+impl DataFileMetadata {
+    pub fn new(id: u128, path: std::path::PathBuf) -> DataFileMetadata {
+        DataFileMetadata {
+            id,
+            path,
+            ..Default::default()
+        }
+    }
+
+    pub fn dead_bytes(&self) -> u64 {
+        self.total_bytes.saturating_sub(self.live_bytes)
+    }
+
+    /// fragmentation is the share of dead bytes, from 0.0 (none) to 1.0 (all of them).
+    pub fn fragmentation(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        self.dead_bytes() as f64 / self.total_bytes as f64
+    }
+
+    // a new record of a key which is live now:
+    pub(crate) fn add_live(&mut self, size: u64) {
+        self.live_keys += 1;
+        self.live_bytes += size;
+        self.total_bytes += size;
+    }
+
+    // a new record which is dead right away, ie. a delete. Batch markers aren't keys:
+    pub(crate) fn add_dead(&mut self, size: u64, is_key: bool) {
+        if is_key {
+            self.dead_keys += 1;
+        }
+        self.total_bytes += size;
+    }
+
+    // a live record of the file got superseded:
+    pub(crate) fn kill(&mut self, size: u64) {
+        self.live_keys = self.live_keys.saturating_sub(1);
+        self.live_bytes = self.live_bytes.saturating_sub(size);
+        self.dead_keys += 1;
+    }
 }
 
 /// CleanFile is a wrapper for File which deletes the file on close
-/// if the file's size is 0:
+/// if the file holds no records (ie. it is empty or only contains the header).
+/// Only writable files are cleaned up, readers must not remove a file someone else writes to:
 #[derive(Debug)]
 struct CleanFile {
     file: Option<std::fs::File>,
     path: std::path::PathBuf,
+    is_readonly: bool,
+    records_start: u64,
 }
 
 impl std::ops::Deref for CleanFile {
@@ -38,11 +101,14 @@ impl std::ops::DerefMut for CleanFile {
 impl Drop for CleanFile {
     fn drop(&mut self) {
         self.file.take();
+        if self.is_readonly {
+            return;
+        }
 
         let path = &self.path.as_path();
         let file_metadata = std::fs::metadata(path);
         if let Ok(metadata) = file_metadata {
-            if metadata.len() == 0 {
+            if metadata.len() <= self.records_start {
                 log::trace!(
                     "Datafile.drop: removing file since its empty {}",
                     path.display()
@@ -57,76 +123,412 @@ impl Drop for CleanFile {
 pub struct DataFile {
     pub id: u128,
     pub is_readonly: bool,
+    pub header: FileHeader,
 
     file: CleanFile,
     pub path: std::path::PathBuf,
+
+    // bytes appended since the last sync:
+    unsynced_bytes: u64,
+
+    // set for encrypted files, their records are sealed one by one:
+    cipher: Option<Cipher>,
 }
 
 impl DataFile {
-    pub fn create(path: &std::path::Path, is_readonly: bool) -> ErrorResult<DataFile> {
+    /// create opens a data file or creates a new one, which is encrypted if a cipher is given.
+    /// Existing files keep their format: encrypted ones need the cipher, plain ones are only
+    /// opened with a cipher which accepts plain files.
+    pub fn create(
+        path: &std::path::Path,
+        is_readonly: bool,
+        cipher: Option<&Cipher>,
+    ) -> ErrorResult<DataFile> {
         let datafile = if is_readonly {
-            OpenOptions::new().read(true).open(&path)?
+            OpenOptions::new().read(true).open(path)?
         } else {
             OpenOptions::new()
                 .read(true)
                 .append(true)
-                .write(true)
                 .create(true)
-                .open(&path)?
+                .open(path)?
         };
 
-        let id = crate::utils::extract_id_from_filename(&path.to_path_buf())?;
+        let id = crate::utils::extract_id_from_filename(path)?;
+
+        // new files get a header, existing ones must carry one we understand:
+        let is_new = datafile.metadata()?.len() == 0;
+        let header = if is_new {
+            let magic = match cipher {
+                Some(_) => ENCRYPTED_DATA_FILE_MAGIC,
+                None => DATA_FILE_MAGIC,
+            };
+            let header = FileHeader::new(magic, DATA_FILE_VERSION, id);
+            if !is_readonly {
+                header.write_to(&datafile)?;
+            }
+            header
+        } else {
+            let magics = [DATA_FILE_MAGIC, ENCRYPTED_DATA_FILE_MAGIC];
+            FileHeader::read_from(
+                &datafile,
+                path,
+                &magics,
+                DATA_FILE_VERSION,
+                EntryV0::is_first,
+            )?
+        };
+
+        let cipher = match header.magic {
+            ENCRYPTED_DATA_FILE_MAGIC => {
+                Some(cipher.cloned().ok_or_else(|| Error::MissingEncryptionKey {
+                    path: path.to_path_buf(),
+                })?)
+            }
+            // an existing plain file while there is a key, ie. written before the key was set:
+            _ if cipher.is_some_and(|cipher| !cipher.accepts_plain_files) && !is_new => {
+                return Err(Error::UnencryptedFile {
+                    path: path.to_path_buf(),
+                })
+            }
+            _ => None,
+        };
 
         let df = DataFile {
             id,
+            header,
             file: CleanFile {
                 file: Some(datafile),
                 path: path.to_path_buf(),
+                is_readonly,
+                records_start: header.records_start(),
             },
             is_readonly,
             path: path.to_path_buf(),
+            unsynced_bytes: 0,
+            cipher,
         };
 
         Ok(df)
     }
 
     pub fn get_id(&self) -> u128 {
-        (self.id as u128)
+        self.id
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
     }
 
     pub fn write(&mut self, key: &[u8], value: &[u8], timestamp: u128) -> ErrorResult<u64> {
-        let entry = Entry {
-            timestamp,
-            key: key.to_vec(),
-            value: value.to_vec(),
-        };
+        let entry = Entry::new(EntryKind::Put, key, value, timestamp);
+        Ok(self.append(&[entry])?[0])
+    }
+
+    pub fn remove(&mut self, key: &[u8], timestamp: u128) -> ErrorResult<u64> {
+        let entry = Entry::new(EntryKind::Delete, key, &[], timestamp);
+        Ok(self.append(&[entry])?[0])
+    }
+
+    /// write_batch appends a batch marker followed by all operations with a single write
+    /// and returns the offset of each operation's record.
+    pub fn write_batch(&mut self, ops: &[BatchOp], timestamp: u128) -> ErrorResult<Vec<u64>> {
+        let mut entries = Vec::with_capacity(ops.len() + 1);
+        entries.push(Entry::batch_begin(ops.len() as u64, timestamp));
+
+        for op in ops {
+            entries.push(Entry::from_op(op, timestamp, None));
+        }
+
+        let mut offsets = self.append(&entries)?;
+        offsets.remove(0);
+        Ok(offsets)
+    }
+
+    /// append writes all entries with a single write and returns the offset of each of them.
+    pub fn append(&mut self, entries: &[Entry]) -> ErrorResult<Vec<u64>> {
+        let records = self.append_records(entries)?;
+        Ok(records.into_iter().map(|(offset, _)| offset).collect())
+    }
+
+    /// append_records is like append, but returns the offset and the size on disk of each record.
+    pub fn append_records(&mut self, entries: &[Entry]) -> ErrorResult<Vec<(u64, u64)>> {
         use std::io::Write as _;
 
-        let offset = self.file.seek(SeekFrom::Current(0))?;
+        let offset = self.file.seek(SeekFrom::End(0))?;
+
         // serialize_into is vastly slower than serializing to avec then doing 1 big write
-        let encoded: Vec<u8> = bincode::serialize(&entry)?;
+        let mut encoded: Vec<u8> = Vec::new();
+        let mut records = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let record_offset = offset + encoded.len() as u64;
+
+            match &self.cipher {
+                Some(cipher) => {
+                    let mut record = Vec::new();
+                    entry.serialize_into(&mut record)?;
+                    let sealed = cipher.seal(self.header.magic, self.id, record_offset, &record);
+                    bincode::serialize_into(&mut encoded, &sealed)?;
+                }
+                None => entry.serialize_into(&mut encoded)?,
+            }
+            records.push((record_offset, offset + encoded.len() as u64 - record_offset));
+        }
+
         self.file.write_all(&encoded)?;
-        Ok(offset)
+        self.unsynced_bytes += encoded.len() as u64;
+        Ok(records)
     }
 
-    pub fn remove(&mut self, key: &[u8], timestamp: u128) -> ErrorResult<u64> {
-        self.write(key, REMOVE_TOMBSTONE, timestamp)
+    pub fn read(&self, offset: u64) -> ErrorResult<Entry> {
+        let mmap = unsafe { memmap::MmapOptions::new().map(&self.file)? };
+        // a keydir or hint offset past the end, ie. of a file which got truncated:
+        let record = mmap
+            .get(offset as usize..)
+            .filter(|record| !record.is_empty())
+            .ok_or(Error::Corruption {
+                file_id: self.id,
+                offset,
+            })?;
+        let decoded = self.decode(record, offset)?;
+
+        decoded.decompress().map_err(|_| Error::Corruption {
+            file_id: self.id,
+            offset,
+        })
     }
 
-    pub fn read(&mut self, offset: u64) -> ErrorResult<Entry> {
-        let mmap = unsafe { memmap::MmapOptions::new().map(&self.file)? };
-        let decoded: Entry = bincode::deserialize(&mmap[(offset as usize)..])?;
+    /// decode reads the record at `offset` and verifies its checksum. Records of encrypted
+    /// files are decrypted, compressed values are returned as they are stored.
+    fn decode<R: std::io::Read>(&self, reader: R, offset: u64) -> ErrorResult<Entry> {
+        let file_id = self.id;
+
+        // running into the end of the file in the middle of a record means the
+        // record was never fully written:
+        let to_error = |err: bincode::Error| match err.as_ref() {
+            bincode::ErrorKind::Io(io_err)
+                if io_err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                Error::TruncatedRecord { file_id, offset }
+            }
+            _ => Error::Corruption { file_id, offset },
+        };
+
+        let (decoded, is_valid) = match &self.cipher {
+            Some(cipher) => {
+                let sealed: Vec<u8> = bincode::deserialize_from(reader).map_err(to_error)?;
+                let record = cipher
+                    .open(self.header.magic, file_id, offset, &sealed)
+                    .ok_or_else(|| Error::Decryption {
+                        path: self.path.clone(),
+                        offset,
+                    })?;
+                Entry::deserialize_from(&record[..], self.header.version)
+                    .map_err(|_| Error::Corruption { file_id, offset })?
+            }
+            None => Entry::deserialize_from(reader, self.header.version).map_err(to_error)?,
+        };
+
+        if !is_valid {
+            return Err(Error::Corruption { file_id, offset });
+        }
+
         Ok(decoded)
     }
 
-    pub fn iter(&mut self) -> DataFileIterator {
-        let file = std::fs::File::open(&self.path).unwrap();
+    /// iter yields every record with its value decompressed.
+    pub fn iter(&self) -> ErrorResult<DataFileIterator<'_>> {
+        self.iterate(true)
+    }
+
+    /// iter_raw yields every record as it is stored, ie. to copy it into another file.
+    pub fn iter_raw(&self) -> ErrorResult<DataFileIterator<'_>> {
+        self.iterate(false)
+    }
+
+    fn iterate(&self, decompress: bool) -> ErrorResult<DataFileIterator<'_>> {
+        let mut file = std::fs::File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(self.header.records_start()))?;
+
+        Ok(DataFileIterator {
+            data_file: self,
+            file,
+            len,
+            decompress,
+            done: false,
+        })
+    }
+
+    /// read_hints scans the whole data file and returns a hint for each of its records.
+    /// Records of a batch are only returned if the batch was completely written.
+    /// With `stop_at_torn_tail` a partially written last record is skipped instead of
+    /// returned as an error, as it can be still in flight when another process writes the file.
+    pub fn read_hints(&mut self, stop_at_torn_tail: bool) -> ErrorResult<Vec<IndexEntry>> {
+        match self.scan()? {
+            (_, Some(offset)) if !stop_at_torn_tail => Err(Error::TruncatedRecord {
+                file_id: self.id,
+                offset,
+            }),
+            (hints, _) => Ok(hints),
+        }
+    }
+
+    /// scan returns the hints of read_hints and the offset of a partially written record at
+    /// the end of the file, if there is one. Records which are corrupted in any other way are
+    /// returned as an error. Values are left compressed, hints only need their size.
+    pub fn scan(&mut self) -> ErrorResult<(Vec<IndexEntry>, Option<u64>)> {
+        let mut hints = Vec::new();
+        let mut torn_offset = None;
+
+        // number of records still missing and the hints collected so far of the open batch:
+        let mut batch: Option<(u64, Vec<IndexEntry>)> = None;
+
+        for item in self.iter_raw()? {
+            let (offset, record) = match item {
+                Err(Error::TruncatedRecord { offset, .. }) => {
+                    self.check_torn_tail(offset)?;
+                    torn_offset = Some(offset);
+                    break;
+                }
+                item => item?,
+            };
+
+            if record.kind == EntryKind::BatchBegin {
+                if batch.is_some() {
+                    log::warn!(
+                        "DataFile.scan: ignoring incomplete batch in data file {}",
+                        self.id
+                    );
+                }
+                let batch_len = record.batch_len();
+                batch = if batch_len > 0 {
+                    Some((batch_len, Vec::new()))
+                } else {
+                    None
+                };
+                continue;
+            }
+
+            let hint = IndexEntry {
+                file_id: self.id,
+                offset,
+                timestamp: record.timestamp,
+                value_size: record.value_size()?,
+                is_tombstone: record.is_tombstone(),
+                expires_at: record.expires_at,
+                key: record.key,
+                size: 0,
+            };
+
+            match batch.as_mut() {
+                Some((missing, batch_hints)) => {
+                    batch_hints.push(hint);
+                    *missing -= 1;
+                }
+                None => hints.push(hint),
+            }
+
+            if let Some((0, _)) = batch {
+                hints.append(&mut batch.take().unwrap().1);
+            }
+        }
+
+        if batch.is_some() {
+            log::warn!(
+                "DataFile.scan: ignoring incomplete batch at the end of data file {}",
+                self.id
+            );
+        }
+
+        Ok((hints, torn_offset))
+    }
+
+    // a record which runs into the end of the file is only a torn tail if no valid record
+    // starts within RESYNC_WINDOW bytes after it. Otherwise ie. a corrupted length made it look
+    // longer than it is. Version 0 records have no checksum which could tell them apart:
+    fn check_torn_tail(&self, offset: u64) -> ErrorResult<()> {
+        let file_id = self.id;
+        if self.header.version == 0 {
+            return Err(Error::TruncatedRecord { file_id, offset });
+        }
+
+        let mmap = unsafe { memmap::MmapOptions::new().map(&self.file)? };
+        let start = offset as usize + 1;
+        let end = mmap.len().min(start + RESYNC_WINDOW);
+        for next in start..end {
+            let record = &mmap[next..];
+            if self.fits(record) && self.decode(record, next as u64).is_ok() {
+                return Err(Error::Corruption { file_id, offset });
+            }
+        }
+
+        Ok(())
+    }
+
+    // fits tells whether the length fields of a record at the start of `buf` stay within it.
+    // It is cheap, unlike decoding a record which claims more bytes than there are:
+    fn fits(&self, buf: &[u8]) -> bool {
+        let u64_at = |pos: usize| -> Option<u64> {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(buf.get(pos..pos.checked_add(8)?)?);
+            Some(u64::from_le_bytes(bytes))
+        };
+        // the position after the length prefixed field at `pos`:
+        let skip_field = |pos: usize| -> Option<usize> {
+            let end = (pos as u64 + 8).checked_add(u64_at(pos)?)?;
+            (end <= buf.len() as u64).then_some(end as usize)
+        };
+
+        let key_pos = || -> Option<usize> {
+            if self.cipher.is_some() {
+                // a sealed record is a single field:
+                return Some(0);
+            }
+            if self.header.version == 1 {
+                // crc and timestamp:
+                return Some(4 + 16);
+            }
 
-        DataFileIterator { file }
+            // crc, kind and timestamp, followed by the expiry or the codec and expiry:
+            let kind = u64_at(4)? as u32;
+            let extra = if kind == EntryKind::PutWithExpiry as u32 {
+                16
+            } else if kind == EntryKind::PutCompressed as u32 {
+                4 + 1 + 16 * usize::from(*buf.get(4 + 4 + 16 + 4)? == 1)
+            } else if kind <= EntryKind::PutCompressed as u32 {
+                0
+            } else {
+                return None;
+            };
+            Some(4 + 4 + 16 + extra)
+        };
+
+        let fits = || -> Option<usize> {
+            let pos = skip_field(key_pos()?)?;
+            if self.cipher.is_some() {
+                return Some(pos);
+            }
+            skip_field(pos)
+        };
+        fits().is_some()
     }
 
     pub fn sync(&mut self) -> ErrorResult<()> {
-        self.file.sync_all().map_err(Into::into)
+        self.file.sync_all()?;
+        self.unsynced_bytes = 0;
+        Ok(())
+    }
+
+    /// sync_data is like sync, but skips flushing metadata which isn't needed to read the records back.
+    pub fn sync_data(&mut self) -> ErrorResult<()> {
+        self.file.sync_data()?;
+        self.unsynced_bytes = 0;
+        Ok(())
+    }
+
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.unsynced_bytes
     }
 
     pub fn inspect(&mut self, with_header: bool) -> String {
@@ -136,20 +538,42 @@ impl DataFile {
             list.push_str(format!("Datafile {}:\n", self.id).as_str());
         }
 
-        for (offset, entry) in self.iter() {
-            let mut op = "S"; // Set
-
-            if entry.value == crate::config::REMOVE_TOMBSTONE {
-                op = "D" // Delete
-            }
+        let iter = match self.iter() {
+            Ok(iter) => iter,
+            Err(err) => return format!("{}{}", list, err),
+        };
 
-            let line = format!(
-                "{:0>8} | {: >1} | {} | {}\n",
-                offset,
-                op,
-                std::str::from_utf8(&entry.key).unwrap(),
-                std::str::from_utf8(&entry.value).unwrap()
-            );
+        for item in iter {
+            let (offset, entry) = match item {
+                Ok(item) => item,
+                Err(err) => {
+                    list.push_str(format!("{}\n", err).as_str());
+                    break;
+                }
+            };
+
+            let line = match entry.kind {
+                // compressed values are already decompressed by the iterator:
+                EntryKind::Put | EntryKind::PutCompressed => format!(
+                    "{:0>8} | S | {} | {}\n",
+                    offset,
+                    String::from_utf8_lossy(&entry.key),
+                    String::from_utf8_lossy(&entry.value)
+                ),
+                EntryKind::PutWithExpiry => format!(
+                    "{:0>8} | T | {} | {} | {}\n",
+                    offset,
+                    String::from_utf8_lossy(&entry.key),
+                    String::from_utf8_lossy(&entry.value),
+                    entry.expires_at.unwrap_or_default()
+                ),
+                EntryKind::Delete => format!(
+                    "{:0>8} | D | {}\n",
+                    offset,
+                    String::from_utf8_lossy(&entry.key)
+                ),
+                EntryKind::BatchBegin => format!("{:0>8} | B | {}\n", offset, entry.batch_len()),
+            };
             list.push_str(&line);
         }
 
@@ -157,30 +581,409 @@ impl DataFile {
     }
 }
 
-pub struct DataFileIterator {
+/// DataFileIterator yields every record of a data file together with its offset.
+/// A record which can't be decoded or has a bad checksum is returned as an error
+/// and ends the iteration.
+pub struct DataFileIterator<'a> {
+    data_file: &'a DataFile,
     file: std::fs::File,
+    len: u64,
+    decompress: bool,
+    done: bool,
 }
 
-impl Iterator for DataFileIterator {
-    type Item = (u64, Entry);
+impl Iterator for DataFileIterator<'_> {
+    type Item = ErrorResult<(u64, Entry)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let offset = self.file.seek(SeekFrom::Current(0)).unwrap();
-        let decoded_maybe = bincode::deserialize_from(&self.file);
-        Some((offset, decoded_maybe.ok()?))
+        if self.done {
+            return None;
+        }
+
+        let offset = match self.file.stream_position() {
+            Ok(offset) => offset,
+            Err(err) => {
+                self.done = true;
+                return Some(Err(err.into()));
+            }
+        };
+        if offset >= self.len {
+            return None;
+        }
+
+        let decoded = self.data_file.decode(&self.file, offset);
+        let file_id = self.data_file.id;
+
+        match decoded {
+            Ok(decoded) if !self.decompress => Some(Ok((offset, decoded))),
+            Ok(decoded) => match decoded.decompress() {
+                Ok(decoded) => Some(Ok((offset, decoded))),
+                Err(_) => {
+                    self.done = true;
+                    Some(Err(Error::Corruption { file_id, offset }))
+                }
+            },
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
     }
 }
 
 impl Drop for DataFile {
     fn drop(&mut self) {
+        // readers have nothing to flush:
+        if self.is_readonly {
+            return;
+        }
         self.file.sync_all().unwrap_or_default();
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// EntryKind tells what a record does with its key. New kinds have to be
+/// appended at the end, the variant index is what ends up on disk.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum EntryKind {
+    Put,
+    Delete,
+    // marks the start of a batch, the value holds the number of records which belong to it:
+    BatchBegin,
+    // a put which is gone after expires_at. Only these records carry the expiry:
+    PutWithExpiry,
+    // a put with a compressed value, it carries the codec and an optional expiry:
+    PutCompressed,
+}
+
+/// Codec tells how the value of a record is compressed. New codecs have to be
+/// appended at the end, the variant index is what ends up on disk.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Codec {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    /// compress returns the compressed value, zstd uses the given level:
+    pub fn compress(self, value: &[u8], level: i32) -> ErrorResult<Vec<u8>> {
+        match self {
+            Codec::None => Ok(value.to_vec()),
+            Codec::Lz4 => Ok(lz4_flex::compress_prepend_size(value)),
+            Codec::Zstd => Ok(zstd::bulk::compress(value, level)?),
+        }
+    }
+
+    /// decompressed_size reads the size of the plain value from the compressed one, if the
+    /// codec stores it.
+    pub fn decompressed_size(self, value: &[u8]) -> Option<u64> {
+        match self {
+            Codec::None => Some(value.len() as u64),
+            Codec::Lz4 => {
+                let mut size = [0u8; 4];
+                size.copy_from_slice(value.get(..4)?);
+                Some(u64::from(u32::from_le_bytes(size)))
+            }
+            Codec::Zstd => zstd::zstd_safe::get_frame_content_size(value).ok()?,
+        }
+    }
+
+    pub fn decompress(self, value: &[u8]) -> ErrorResult<Vec<u8>> {
+        match self {
+            Codec::None => Ok(value.to_vec()),
+            Codec::Lz4 => lz4_flex::decompress_size_prepended(value)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err).into()),
+            Codec::Zstd => Ok(zstd::stream::decode_all(value)?),
+        }
+    }
+}
+
+/// Entry is a single record of a data file. On disk it is crc, kind, timestamp, the
+/// expiry (only for `PutWithExpiry`) or codec and expiry (only for `PutCompressed`),
+/// key and value, each encoded with bincode.
+#[derive(PartialEq, Debug)]
 pub struct Entry {
-    // TODO: crc: impl later
+    // crc32 over kind, timestamp, codec, expiry, key and the stored value:
+    pub crc: u32,
+    pub kind: EntryKind,
     pub timestamp: u128,
+    pub expires_at: Option<u128>,
+    // values are stored as they are on disk, decompress returns the plain value:
+    pub codec: Codec,
     pub key: Vec<u8>,
     pub value: Vec<u8>,
 }
+
+impl Entry {
+    pub fn new(kind: EntryKind, key: &[u8], value: &[u8], timestamp: u128) -> Entry {
+        Entry {
+            crc: Self::checksum(kind, timestamp, Codec::None, None, key, value),
+            kind,
+            timestamp,
+            expires_at: None,
+            codec: Codec::None,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    /// put creates a put record, which expires at `expires_at` if it is given.
+    pub fn put(key: &[u8], value: &[u8], timestamp: u128, expires_at: Option<u128>) -> Entry {
+        if expires_at.is_none() {
+            return Entry::new(EntryKind::Put, key, value, timestamp);
+        }
+
+        let kind = EntryKind::PutWithExpiry;
+        Entry {
+            crc: Self::checksum(kind, timestamp, Codec::None, expires_at, key, value),
+            kind,
+            timestamp,
+            expires_at,
+            codec: Codec::None,
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    /// compress stores the value of a put compressed with the codec, if it is at least
+    /// `min_size` bytes long and gets smaller. Other records are returned as they are.
+    pub fn compress(self, codec: Codec, level: i32, min_size: u64) -> ErrorResult<Entry> {
+        let is_put = matches!(self.kind, EntryKind::Put | EntryKind::PutWithExpiry);
+        if codec == Codec::None || !is_put || (self.value.len() as u64) < min_size {
+            return Ok(self);
+        }
+
+        let value = codec.compress(&self.value, level)?;
+        if value.len() >= self.value.len() {
+            return Ok(self);
+        }
+
+        let kind = EntryKind::PutCompressed;
+        Ok(Entry {
+            crc: Self::checksum(
+                kind,
+                self.timestamp,
+                codec,
+                self.expires_at,
+                &self.key,
+                &value,
+            ),
+            kind,
+            timestamp: self.timestamp,
+            expires_at: self.expires_at,
+            codec,
+            key: self.key,
+            value,
+        })
+    }
+
+    /// decompress returns the record with its plain value, as if it was never compressed.
+    pub fn decompress(self) -> ErrorResult<Entry> {
+        if self.kind != EntryKind::PutCompressed {
+            return Ok(self);
+        }
+
+        let value = self.codec.decompress(&self.value)?;
+        Ok(Entry::put(
+            &self.key,
+            &value,
+            self.timestamp,
+            self.expires_at,
+        ))
+    }
+
+    pub fn from_op(op: &BatchOp, timestamp: u128, expires_at: Option<u128>) -> Entry {
+        match op {
+            BatchOp::Put { key, value } => Entry::put(key, value, timestamp, expires_at),
+            BatchOp::Delete { key } => Entry::new(EntryKind::Delete, key, &[], timestamp),
+        }
+    }
+
+    pub fn batch_begin(len: u64, timestamp: u128) -> Entry {
+        Entry::new(EntryKind::BatchBegin, &[], &len.to_le_bytes(), timestamp)
+    }
+
+    pub fn batch_len(&self) -> u64 {
+        let mut len = [0u8; 8];
+        if let Some(bytes) = self.value.get(..8) {
+            len.copy_from_slice(bytes);
+        }
+        u64::from_le_bytes(len)
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.kind == EntryKind::Delete
+    }
+
+    /// value_size is the size of the plain value, compressed values are only decompressed
+    /// if their codec didn't record it.
+    pub fn value_size(&self) -> ErrorResult<u64> {
+        if self.kind != EntryKind::PutCompressed {
+            return Ok(self.value.len() as u64);
+        }
+
+        match self.codec.decompressed_size(&self.value) {
+            Some(size) => Ok(size),
+            None => Ok(self.codec.decompress(&self.value)?.len() as u64),
+        }
+    }
+
+    pub fn is_expired(&self, now: u128) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.crc
+            == Self::checksum(
+                self.kind,
+                self.timestamp,
+                self.codec,
+                self.expires_at,
+                &self.key,
+                &self.value,
+            )
+    }
+
+    pub fn serialize_into(&self, buf: &mut Vec<u8>) -> bincode::Result<()> {
+        bincode::serialize_into(&mut *buf, &(self.crc, self.kind, self.timestamp))?;
+        match self.kind {
+            EntryKind::PutCompressed => {
+                bincode::serialize_into(&mut *buf, &(self.codec, self.expires_at))?
+            }
+            _ => {
+                if let Some(expires_at) = self.expires_at {
+                    bincode::serialize_into(&mut *buf, &expires_at)?;
+                }
+            }
+        }
+        bincode::serialize_into(&mut *buf, &self.key)?;
+        bincode::serialize_into(buf, &self.value)
+    }
+
+    /// Decodes a record written with the given data file format version and
+    /// returns it together with the result of its checksum verification.
+    pub fn deserialize_from<R: std::io::Read>(
+        mut reader: R,
+        version: u16,
+    ) -> bincode::Result<(Entry, bool)> {
+        if version == 0 {
+            let legacy: EntryV0 = bincode::deserialize_from(reader)?;
+            return Ok((legacy.into(), true));
+        }
+
+        if version == 1 {
+            let legacy: EntryV1 = bincode::deserialize_from(reader)?;
+            let is_valid = legacy.is_valid();
+            return Ok((legacy.into(), is_valid));
+        }
+
+        // versions 2 and 3 are the same, they just never contain records with
+        // an expiry (version 2) or a compressed value (versions 2 and 3):
+        let (crc, kind, timestamp): (u32, EntryKind, u128) =
+            bincode::deserialize_from(&mut reader)?;
+        let (codec, expires_at) = match kind {
+            EntryKind::PutWithExpiry => {
+                (Codec::None, Some(bincode::deserialize_from(&mut reader)?))
+            }
+            EntryKind::PutCompressed => bincode::deserialize_from(&mut reader)?,
+            _ => (Codec::None, None),
+        };
+
+        let entry = Entry {
+            crc,
+            kind,
+            timestamp,
+            expires_at,
+            codec,
+            key: bincode::deserialize_from(&mut reader)?,
+            value: bincode::deserialize_from(&mut reader)?,
+        };
+        let is_valid = entry.is_valid();
+        Ok((entry, is_valid))
+    }
+
+    fn checksum(
+        kind: EntryKind,
+        timestamp: u128,
+        codec: Codec,
+        expires_at: Option<u128>,
+        key: &[u8],
+        value: &[u8],
+    ) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&(kind as u32).to_le_bytes());
+        hasher.update(&timestamp.to_le_bytes());
+        // uncompressed records don't cover the codec, so their checksum stays as it was:
+        if codec != Codec::None {
+            hasher.update(&(codec as u32).to_le_bytes());
+        }
+        if let Some(expires_at) = expires_at {
+            hasher.update(&expires_at.to_le_bytes());
+        }
+        // the lengths are part of the checksum so bytes can't shift between key and value:
+        hasher.update(&(key.len() as u64).to_le_bytes());
+        hasher.update(key);
+        hasher.update(&(value.len() as u64).to_le_bytes());
+        hasher.update(value);
+        hasher.finalize()
+    }
+}
+
+/// EntryV0 is the record format of the headerless version 0 data files. It has no
+/// checksum and, like version 1, stores a delete as a put of the REMOVE_TOMBSTONE value.
+#[derive(Deserialize)]
+struct EntryV0 {
+    timestamp: u128,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl EntryV0 {
+    // a headerless file is only taken for a version 0 data file if it starts with a
+    // complete record, version 0 timestamps are never 0:
+    fn is_first(file: &std::fs::File) -> bool {
+        bincode::deserialize_from::<_, EntryV0>(file).is_ok_and(|entry| entry.timestamp > 0)
+    }
+}
+
+impl From<EntryV0> for Entry {
+    fn from(legacy: EntryV0) -> Entry {
+        if legacy.value == REMOVE_TOMBSTONE {
+            return Entry::new(EntryKind::Delete, &legacy.key, &[], legacy.timestamp);
+        }
+
+        Entry::new(EntryKind::Put, &legacy.key, &legacy.value, legacy.timestamp)
+    }
+}
+
+/// EntryV1 is the record format of version 1 data files, where a delete was
+/// stored as a put of the REMOVE_TOMBSTONE value.
+#[derive(Deserialize)]
+struct EntryV1 {
+    crc: u32,
+    timestamp: u128,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl EntryV1 {
+    fn is_valid(&self) -> bool {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.timestamp.to_le_bytes());
+        hasher.update(&(self.key.len() as u64).to_le_bytes());
+        hasher.update(&self.key);
+        hasher.update(&(self.value.len() as u64).to_le_bytes());
+        hasher.update(&self.value);
+        self.crc == hasher.finalize()
+    }
+}
+
+impl From<EntryV1> for Entry {
+    fn from(legacy: EntryV1) -> Entry {
+        if legacy.value == REMOVE_TOMBSTONE {
+            return Entry::new(EntryKind::Delete, &legacy.key, &[], legacy.timestamp);
+        }
+
+        Entry::new(EntryKind::Put, &legacy.key, &legacy.value, legacy.timestamp)
+    }
+}
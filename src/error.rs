@@ -1,16 +1,150 @@
+use std::sync::Arc;
+
 use snafu::Snafu;
 
-#[derive(Debug, Snafu)]
+/// Error is returned by all public methods of the database. It is cheap to clone, ie.
+/// for every writer of a failed group commit, as the errors it wraps are shared.
+#[derive(Clone, Debug, Snafu)]
 pub enum Error {
+    #[snafu(display("Key not found: {}", String::from_utf8_lossy(key)))]
+    NotFound { key: Vec<u8> },
+
+    #[snafu(display("I/O error: {}", source))]
+    Io { source: Arc<std::io::Error> },
+
+    #[snafu(display("Failed to encode or decode an entry: {}", source))]
+    Serialization { source: Arc<bincode::Error> },
+
     #[snafu(display("Failed to create base dir for db from path '{}': {}", path.display(), source))]
     CreateDatabaseDir {
         path: std::path::PathBuf,
-        source: std::io::Error,
+        source: Arc<std::io::Error>,
     },
 
     #[snafu(display("Failed to fill keydir from path '{}': {}", path.display(), source))]
     KeyDirFill {
         path: std::path::PathBuf,
-        source: Box<dyn std::error::Error>,
+        source: Box<Error>,
+    },
+
+    #[snafu(display("Failed to load '{}': {}", path.display(), source))]
+    FileLoad {
+        path: std::path::PathBuf,
+        source: Box<Error>,
+    },
+
+    #[snafu(display("Corrupted record in data file {} at offset {}", file_id, offset))]
+    Corruption { file_id: u128, offset: u64 },
+
+    #[snafu(display(
+        "Incomplete record in data file {} at offset {} (the write was interrupted)",
+        file_id,
+        offset
+    ))]
+    TruncatedRecord { file_id: u128, offset: u64 },
+
+    #[snafu(display(
+        "Failed to decrypt the record at offset {} of '{}' (wrong encryption key or modified file)",
+        offset,
+        path.display()
+    ))]
+    Decryption {
+        path: std::path::PathBuf,
+        offset: u64,
+    },
+
+    #[snafu(display("'{}' is encrypted, but no encryption key was given", path.display()))]
+    MissingEncryptionKey { path: std::path::PathBuf },
+
+    #[snafu(display(
+        "'{}' is not encrypted, but an encryption key was given (see Options::allow_plain_files)",
+        path.display()
+    ))]
+    UnencryptedFile { path: std::path::PathBuf },
+
+    #[snafu(display("Invalid file format of '{}': {}", path.display(), reason))]
+    InvalidFormat {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+
+    #[snafu(display(
+        "Unsupported format version {} of '{}' (this build reads up to version {})",
+        version,
+        path.display(),
+        supported
+    ))]
+    UnsupportedVersion {
+        path: std::path::PathBuf,
+        version: u16,
+        supported: u16,
+    },
+
+    #[snafu(display(
+        "Database directory '{}' is already in use (lock file '{}' is held)",
+        path.display(),
+        lock_path.display()
+    ))]
+    Locked {
+        path: std::path::PathBuf,
+        lock_path: std::path::PathBuf,
+    },
+
+    #[snafu(display("Invalid file name '{}' (expected format: <name>.<id>)", path.display()))]
+    InvalidFileName { path: std::path::PathBuf },
+
+    #[snafu(display("Path '{}' is not valid UTF-8", path.display()))]
+    InvalidPath { path: std::path::PathBuf },
+
+    #[snafu(display("Invalid glob pattern '{}': {}", pattern, source))]
+    InvalidPattern {
+        pattern: String,
+        source: Arc<glob::PatternError>,
     },
+
+    #[snafu(display("Database directory '{}' does not exist", path.display()))]
+    DatabaseDirNotFound { path: std::path::PathBuf },
+
+    #[snafu(display("Database has been closed"))]
+    Closed,
+
+    #[snafu(display("Database was opened read-only"))]
+    ReadOnly,
+
+    #[snafu(display("Merge got cancelled"))]
+    Cancelled,
+
+    #[snafu(display("Group commit failed: {}", reason))]
+    GroupCommit { reason: String },
+}
+
+impl Error {
+    /// root returns the error behind the files which failed to load, ie. to match on
+    /// the `Corruption` a startup failed with.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::KeyDirFill { source, .. } | Error::FileLoad { source, .. } => source.root(),
+            err => err,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(source: std::io::Error) -> Error {
+        Error::Io {
+            source: Arc::new(source),
+        }
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(source: bincode::Error) -> Error {
+        // failed reads and writes stay io errors:
+        match *source {
+            bincode::ErrorKind::Io(source) => source.into(),
+            _ => Error::Serialization {
+                source: Arc::new(source),
+            },
+        }
+    }
 }
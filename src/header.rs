@@ -0,0 +1,130 @@
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::ErrorResult;
+
+pub const DATA_FILE_MAGIC: [u8; 4] = *b"BCDF";
+pub const INDEX_FILE_MAGIC: [u8; 4] = *b"BCIX";
+
+// files whose records are encrypted:
+pub const ENCRYPTED_DATA_FILE_MAGIC: [u8; 4] = *b"BCDE";
+pub const ENCRYPTED_INDEX_FILE_MAGIC: [u8; 4] = *b"BCIE";
+
+const KNOWN_MAGICS: [[u8; 4]; 4] = [
+    DATA_FILE_MAGIC,
+    INDEX_FILE_MAGIC,
+    ENCRYPTED_DATA_FILE_MAGIC,
+    ENCRYPTED_INDEX_FILE_MAGIC,
+];
+
+// Bump these whenever the record format changes. Older versions must stay readable.
+// Version 0 files were written before files got a header:
+pub const DATA_FILE_VERSION: u16 = 4;
+pub const INDEX_FILE_VERSION: u16 = 3;
+
+/// FileHeader is written once at the start of every data and index file.
+/// Records start right after it, at offset `FileHeader::SIZE`, or at offset 0 for
+/// files of version 0 which have no header.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct FileHeader {
+    pub magic: [u8; 4],
+    pub version: u16,
+    pub file_id: u128,
+    pub created_at: u128,
+}
+
+impl FileHeader {
+    // magic (4) + version (2) + file_id (16) + created_at (16):
+    pub const SIZE: u64 = 38;
+
+    pub fn new(magic: [u8; 4], version: u16, file_id: u128) -> FileHeader {
+        FileHeader {
+            magic,
+            version,
+            file_id,
+            created_at: crate::utils::time(),
+        }
+    }
+
+    pub fn records_start(&self) -> u64 {
+        if self.version == 0 {
+            0
+        } else {
+            FileHeader::SIZE
+        }
+    }
+
+    pub fn write_to(&self, mut file: &std::fs::File) -> ErrorResult<()> {
+        let encoded: Vec<u8> = bincode::serialize(self)?;
+        file.write_all(&encoded)?;
+        Ok(())
+    }
+
+    /// Reads the header from the start of `file` and checks it against the expected
+    /// magic bytes (any of them) and the newest version this build knows how to read.
+    /// A file which doesn't start with any known magic bytes is a headerless version 0
+    /// file if `is_v0_record` accepts its first record, it gets the first (plain) magic of
+    /// `magics`. Any other file is refused.
+    pub fn read_from(
+        mut file: &std::fs::File,
+        path: &Path,
+        magics: &[[u8; 4]],
+        supported_version: u16,
+        is_v0_record: fn(&std::fs::File) -> bool,
+    ) -> ErrorResult<FileHeader> {
+        let mut magic = Vec::with_capacity(4);
+        file.take(4).read_to_end(&mut magic)?;
+        if !KNOWN_MAGICS.iter().any(|known| known[..] == magic[..]) {
+            file.seek(SeekFrom::Start(0))?;
+            if !is_v0_record(file) {
+                return Err(Error::InvalidFormat {
+                    path: path.to_path_buf(),
+                    reason: format!("expected magic bytes {:?} but found {:?}", magics, magic),
+                });
+            }
+
+            return Ok(FileHeader {
+                magic: magics[0],
+                version: 0,
+                file_id: crate::utils::extract_id_from_filename(path)?,
+                created_at: 0,
+            });
+        }
+
+        let mut buf = [0u8; FileHeader::SIZE as usize];
+        buf[..4].copy_from_slice(&magic);
+        if file.read_exact(&mut buf[4..]).is_err() {
+            return Err(Error::InvalidFormat {
+                path: path.to_path_buf(),
+                reason: "file is too short to contain a header".to_owned(),
+            });
+        }
+
+        let header: FileHeader = bincode::deserialize(&buf)?;
+        if !magics.contains(&header.magic) {
+            return Err(Error::InvalidFormat {
+                path: path.to_path_buf(),
+                reason: format!(
+                    "expected magic bytes {:?} but found {:?}",
+                    magics, header.magic
+                ),
+            });
+        }
+
+        if header.version == 0 || header.version > supported_version {
+            return Err(Error::UnsupportedVersion {
+                path: path.to_path_buf(),
+                version: header.version,
+                supported: supported_version,
+            });
+        }
+
+        Ok(header)
+    }
+}
@@ -2,40 +2,91 @@ use std::fs::OpenOptions;
 use std::io::Seek;
 use std::io::SeekFrom;
 use std::io::Write;
+use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 
+use crate::cipher::Cipher;
+use crate::header::{FileHeader, ENCRYPTED_INDEX_FILE_MAGIC, INDEX_FILE_MAGIC, INDEX_FILE_VERSION};
 use crate::*;
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct IndexFile {
     pub id: u128,
     pub is_readonly: bool,
+    pub header: FileHeader,
 
     file: std::fs::File,
     pub path: std::path::PathBuf,
+
+    // set for encrypted files, their entries are sealed one by one:
+    cipher: Option<Cipher>,
 }
 
 impl IndexFile {
-    pub fn create(path: &std::path::Path, is_readonly: bool) -> ErrorResult<IndexFile> {
+    /// create opens a hint file or creates a new one, see `DataFile::create` for encryption.
+    pub fn create(
+        path: &std::path::Path,
+        is_readonly: bool,
+        cipher: Option<&Cipher>,
+    ) -> ErrorResult<IndexFile> {
         let indexfile = if is_readonly {
-            OpenOptions::new().read(true).open(&path)?
+            OpenOptions::new().read(true).open(path)?
         } else {
             OpenOptions::new()
                 .read(true)
                 .append(true)
-                .write(true)
                 .create(true)
-                .open(&path)?
+                .open(path)?
+        };
+
+        let id = crate::utils::extract_id_from_filename(path)?;
+
+        let is_new = indexfile.metadata()?.len() == 0;
+        let header = if is_new {
+            let magic = match cipher {
+                Some(_) => ENCRYPTED_INDEX_FILE_MAGIC,
+                None => INDEX_FILE_MAGIC,
+            };
+            let header = FileHeader::new(magic, INDEX_FILE_VERSION, id);
+            if !is_readonly {
+                header.write_to(&indexfile)?;
+            }
+            header
+        } else {
+            let magics = [INDEX_FILE_MAGIC, ENCRYPTED_INDEX_FILE_MAGIC];
+            FileHeader::read_from(
+                &indexfile,
+                path,
+                &magics,
+                INDEX_FILE_VERSION,
+                IndexEntryV1::is_first,
+            )?
         };
 
-        let id = crate::utils::extract_id_from_filename(&path.to_path_buf())?;
+        let cipher = match header.magic {
+            ENCRYPTED_INDEX_FILE_MAGIC => {
+                Some(cipher.cloned().ok_or_else(|| Error::MissingEncryptionKey {
+                    path: path.to_path_buf(),
+                })?)
+            }
+            // an existing plain file while there is a key, ie. written before the key was set:
+            _ if cipher.is_some_and(|cipher| !cipher.accepts_plain_files) && !is_new => {
+                return Err(Error::UnencryptedFile {
+                    path: path.to_path_buf(),
+                })
+            }
+            _ => None,
+        };
 
         let idxfile = IndexFile {
             id,
+            header,
             file: indexfile,
             is_readonly,
             path: path.to_path_buf(),
+            cipher,
         };
 
         Ok(idxfile)
@@ -45,60 +96,124 @@ impl IndexFile {
     //     (self.id as u128)
     // }
 
-    pub fn write(
-        &mut self,
-        key: &[u8],
+    /// write_hints writes a complete hint file 'index.<file_id>' for a data file.
+    /// The entries go to a temporary file first which is renamed once it is synced,
+    /// therefore startup never sees a partially written hint file.
+    pub fn write_hints(
+        base_dir: &Path,
         file_id: u128,
-        offset: u64,
-        timestamp: u128,
-    ) -> ErrorResult<u64> {
-        let entry = IndexEntry {
-            key: key.to_vec(),
-            file_id,
-            offset,
-            timestamp,
-        };
-
-        let offset = self.file.seek(SeekFrom::Current(0))?;
+        entries: &[IndexEntry],
+        cipher: Option<&Cipher>,
+    ) -> ErrorResult<()> {
+        let tmp_path = base_dir.join(crate::config::index_tmp_file_format(file_id));
+        let index_path = base_dir.join(crate::config::index_file_format(file_id));
 
-        let encoded: Vec<u8> = bincode::serialize(&entry)?;
+        let mut index = IndexFile::create(&tmp_path, false, cipher)?;
 
-        let written = self.file.write(&encoded);
-        if let Err(err_msg) = written {
-            return Err(Box::new(err_msg));
+        let mut encoded: Vec<u8> = Vec::new();
+        for entry in entries {
+            let offset = FileHeader::SIZE + encoded.len() as u64;
+            index.encode_into(&mut encoded, entry, offset)?;
         }
+        index.file.write_all(&encoded)?;
+        index.file.sync_all()?;
+        drop(index);
+
+        std::fs::rename(&tmp_path, &index_path)?;
 
-        Ok(offset)
+        Ok(())
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>, entry: &IndexEntry, offset: u64) -> ErrorResult<()> {
+        match &self.cipher {
+            Some(cipher) => {
+                let encoded = bincode::serialize(entry)?;
+                let sealed = cipher.seal(self.header.magic, self.id, offset, &encoded);
+                bincode::serialize_into(buf, &sealed)?;
+            }
+            None => bincode::serialize_into(buf, entry)?,
+        }
+        Ok(())
     }
 
     #[allow(dead_code)]
     pub fn read(&mut self, offset: u64) -> ErrorResult<IndexEntry> {
         self.file.seek(SeekFrom::Start(offset))?;
 
-        let decoded: IndexEntry = bincode::deserialize_from(&self.file)?;
+        self.decode(&self.file, offset)
+    }
 
-        Ok(decoded)
+    /// decode reads the entry at `offset`, entries of encrypted files are decrypted.
+    fn decode<R: std::io::Read>(&self, reader: R, offset: u64) -> ErrorResult<IndexEntry> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok(IndexEntry::deserialize_from(reader, self.header.version)?),
+        };
+
+        let sealed: Vec<u8> = bincode::deserialize_from(reader)?;
+        let encoded = cipher
+            .open(self.header.magic, self.id, offset, &sealed)
+            .ok_or_else(|| Error::Decryption {
+                path: self.path.clone(),
+                offset,
+            })?;
+        Ok(IndexEntry::deserialize_from(
+            &encoded[..],
+            self.header.version,
+        )?)
     }
 
-    pub fn iter(&mut self) -> IndexFileIterator {
-        let file = std::fs::File::open(&self.path).unwrap();
+    pub fn iter(&self) -> ErrorResult<IndexFileIterator<'_>> {
+        let mut file = std::fs::File::open(&self.path)?;
+        let len = file.metadata()?.len();
+        file.seek(SeekFrom::Start(self.header.records_start()))?;
 
-        IndexFileIterator { file }
+        Ok(IndexFileIterator {
+            index_file: self,
+            file,
+            len,
+            done: false,
+        })
     }
 }
 
-pub struct IndexFileIterator {
+/// IndexFileIterator yields every entry of a hint file together with its offset.
+/// An entry which can't be decoded is returned as an error and ends the iteration.
+pub struct IndexFileIterator<'a> {
+    index_file: &'a IndexFile,
     file: std::fs::File,
+    len: u64,
+    done: bool,
 }
 
-impl Iterator for IndexFileIterator {
-    type Item = (u64, IndexEntry);
+impl Iterator for IndexFileIterator<'_> {
+    type Item = ErrorResult<(u64, IndexEntry)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let offset = self.file.seek(SeekFrom::Current(0)).unwrap();
+        if self.done {
+            return None;
+        }
 
-        let decoded_maybe = bincode::deserialize_from(&self.file);
-        Some((offset, decoded_maybe.ok()?))
+        let decoded = self
+            .file
+            .stream_position()
+            .map_err(Error::from)
+            .and_then(|offset| {
+                if offset >= self.len {
+                    return Ok(None);
+                }
+                let decoded = self.index_file.decode(&self.file, offset)?;
+                Ok(Some((offset, decoded)))
+            });
+
+        // hint files are written completely or not at all, so a failure is never the end:
+        match decoded {
+            Ok(decoded) => decoded.map(Ok),
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
     }
 }
 
@@ -108,7 +223,10 @@ impl Drop for IndexFile {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+/// IndexEntry is a hint for one record of a data file. Hint files written on
+/// rotation contain every record (incl. deletes), merged ones only live keys
+/// and the deletes which are still needed.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct IndexEntry {
     pub key: Vec<u8>,
 
@@ -116,4 +234,90 @@ pub struct IndexEntry {
     pub file_id: u128,
     pub offset: u64,
     pub timestamp: u128,
+    pub value_size: u64,
+    pub is_tombstone: bool,
+    pub expires_at: Option<u128>,
+
+    // size of the record on disk. Not stored, it is known once the hints are loaded:
+    #[serde(skip)]
+    pub size: u64,
+}
+
+impl IndexEntry {
+    /// Decodes an entry written with the given index file format version.
+    pub fn deserialize_from<R: std::io::Read>(
+        reader: R,
+        version: u16,
+    ) -> bincode::Result<IndexEntry> {
+        // the entries of headerless version 0 files are the same as those of version 1:
+        if version <= 1 {
+            let legacy: IndexEntryV1 = bincode::deserialize_from(reader)?;
+            return Ok(legacy.into());
+        }
+
+        if version == 2 {
+            let legacy: IndexEntryV2 = bincode::deserialize_from(reader)?;
+            return Ok(legacy.into());
+        }
+
+        bincode::deserialize_from(reader)
+    }
+}
+
+/// IndexEntryV1 is the entry format of version 1 index files, which were
+/// only written by merge and therefore never contain deletes.
+#[derive(Deserialize)]
+struct IndexEntryV1 {
+    key: Vec<u8>,
+    file_id: u128,
+    offset: u64,
+    timestamp: u128,
+}
+
+impl IndexEntryV1 {
+    // see EntryV0::is_first, headerless hint files hold these entries:
+    fn is_first(file: &std::fs::File) -> bool {
+        bincode::deserialize_from::<_, IndexEntryV1>(file).is_ok_and(|entry| entry.timestamp > 0)
+    }
+}
+
+impl From<IndexEntryV1> for IndexEntry {
+    fn from(legacy: IndexEntryV1) -> IndexEntry {
+        IndexEntry {
+            key: legacy.key,
+            file_id: legacy.file_id,
+            offset: legacy.offset,
+            timestamp: legacy.timestamp,
+            value_size: 0,
+            is_tombstone: false,
+            expires_at: None,
+            size: 0,
+        }
+    }
+}
+
+/// IndexEntryV2 is the entry format of version 2 index files, written before keys could expire.
+#[derive(Deserialize)]
+struct IndexEntryV2 {
+    key: Vec<u8>,
+    file_id: u128,
+    offset: u64,
+    timestamp: u128,
+    value_size: u64,
+    is_tombstone: bool,
+}
+
+impl From<IndexEntryV2> for IndexEntry {
+    fn from(legacy: IndexEntryV2) -> IndexEntry {
+        IndexEntry {
+            key: legacy.key,
+            file_id: legacy.file_id,
+            offset: legacy.offset,
+            timestamp: legacy.timestamp,
+            value_size: legacy.value_size,
+            is_tombstone: legacy.is_tombstone,
+            expires_at: None,
+            size: 0,
+        }
+    }
 }
@@ -1,5 +1,6 @@
 use std::collections::BTreeMap;
 
+use crate::error::Error;
 use crate::ErrorResult;
 
 #[derive(Debug, Clone, Copy)]
@@ -7,8 +8,19 @@ pub struct KeyDirEntry {
     pub file_id: u128,
     pub offset: u64,
     pub timestamp: u128,
+    pub expires_at: Option<u128>,
+    // size of the record on disk:
+    pub size: u64,
 }
 
+impl KeyDirEntry {
+    pub fn is_expired(&self, now: u128) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// KeyDir holds the newest record of every key. Expired keys stay until their data file
+/// gets merged or the next startup, but they are not returned anymore.
 #[derive(Default)]
 pub struct KeyDir {
     entries: BTreeMap<Vec<u8>, KeyDirEntry>,
@@ -19,57 +31,88 @@ impl KeyDir {
         Self::default()
     }
 
+    /// set stores the newest record of a key and returns the record it replaces.
     pub fn set(
         &mut self,
         key: &[u8],
         file_id: u128,
         offset: u64,
         timestamp: u128,
-    ) -> ErrorResult<()> {
+        expires_at: Option<u128>,
+        size: u64,
+    ) -> ErrorResult<Option<KeyDirEntry>> {
         log::trace!(
-            "set key={} ts={} offset={} file_id={}",
-            std::str::from_utf8(key)?,
+            "set key={} ts={} offset={} file_id={} expires_at={:?}",
+            String::from_utf8_lossy(key),
             timestamp,
             offset,
-            file_id
+            file_id,
+            expires_at
         );
 
         // XXX: insert works as "upsert":
-        self.entries.insert(
+        Ok(self.entries.insert(
             key.to_vec(),
             KeyDirEntry {
                 file_id,
                 offset,
                 timestamp,
+                expires_at,
+                size,
             },
-        );
-
-        Ok(())
+        ))
     }
 
     // TODO this should probably return a reference to the KeyDirEntry
     pub fn get(&self, key: &[u8]) -> ErrorResult<KeyDirEntry> {
-        // TODO this can just be an ok_or_else
-        if !self.entries.contains_key(key) {
-            let key_str = format!("key not found: {}", std::str::from_utf8(key)?);
-            return Err(string_error::new_err(key_str.as_str()));
+        // expired keys are only dropped once their data file got merged, until then they
+        // are not found:
+        let now = crate::utils::time();
+        match self.entries.get(key) {
+            Some(entry) if !entry.is_expired(now) => Ok(*entry),
+            _ => Err(Error::NotFound { key: key.to_vec() }),
+        }
+    }
+
+    /// relocate points a key to the copy of its record written by merge, unless the key
+    /// got written again in the meantime. Returns whether the key got relocated.
+    pub fn relocate(
+        &mut self,
+        key: &[u8],
+        from: &KeyDirEntry,
+        file_id: u128,
+        offset: u64,
+        size: u64,
+    ) -> bool {
+        match self.entries.get_mut(key) {
+            Some(entry) if entry.file_id == from.file_id && entry.offset == from.offset => {
+                entry.file_id = file_id;
+                entry.offset = offset;
+                entry.size = size;
+                true
+            }
+            _ => false,
         }
-        let entry = self.entries.get(key).cloned().unwrap();
-        Ok(entry)
     }
 
-    // TODO this result is never made
-    pub fn remove(&mut self, key: &[u8]) -> ErrorResult<()> {
-        self.entries.remove(&key.to_vec());
-        Ok(())
+    /// remove_expired drops the keys which are expired by `now` and whose record is in
+    /// one of the given data files, ie. once merge left these records behind.
+    pub fn remove_expired(&mut self, file_ids: &[u128], now: u128) {
+        self.entries
+            .retain(|_, entry| !(entry.is_expired(now) && file_ids.contains(&entry.file_id)));
+    }
+
+    /// remove drops a key and returns the record it pointed to.
+    pub fn remove(&mut self, key: &[u8]) -> ErrorResult<Option<KeyDirEntry>> {
+        Ok(self.entries.remove(key))
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (&Vec<u8>, &KeyDirEntry)> {
-        self.entries.iter()
+        unexpired(self.entries.iter())
     }
 
     pub fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
-        self.entries.keys()
+        self.iter().map(|(key, _)| key)
     }
 
     pub fn keys_range(
@@ -79,17 +122,26 @@ impl KeyDir {
     ) -> impl Iterator<Item = (&Vec<u8>, &KeyDirEntry)> {
         use std::ops::Bound::Included;
 
-        self.entries
-            .range::<[u8], _>((Included(min), Included(max)))
+        unexpired(
+            self.entries
+                .range::<[u8], _>((Included(min), Included(max))),
+        )
     }
 
     pub fn keys_range_min(&self, min: &[u8]) -> impl Iterator<Item = (&Vec<u8>, &KeyDirEntry)> {
         use std::ops::Bound::{Included, Unbounded};
-        self.entries.range::<[u8], _>((Included(min), Unbounded))
+        unexpired(self.entries.range::<[u8], _>((Included(min), Unbounded)))
     }
 
     pub fn keys_range_max(&self, max: &[u8]) -> impl Iterator<Item = (&Vec<u8>, &KeyDirEntry)> {
         use std::ops::Bound::{Included, Unbounded};
-        self.entries.range::<[u8], _>((Unbounded, Included(max)))
+        unexpired(self.entries.range::<[u8], _>((Unbounded, Included(max))))
     }
 }
+
+fn unexpired<'a>(
+    entries: impl Iterator<Item = (&'a Vec<u8>, &'a KeyDirEntry)>,
+) -> impl Iterator<Item = (&'a Vec<u8>, &'a KeyDirEntry)> {
+    let now = crate::utils::time();
+    entries.filter(move |(_, entry)| !entry.is_expired(now))
+}
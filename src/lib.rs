@@ -1,17 +1,28 @@
+mod batch;
+mod cipher;
+mod commit;
 mod config;
 mod database;
 mod datafile;
 mod error;
+mod header;
 mod indexfile;
 mod keydir;
+mod lockfile;
+mod manifest;
+mod throttle;
 mod utils;
 
+pub use batch::{BatchOp, WriteBatch};
+pub use cipher::EncryptionKey;
 pub use database::Database;
 pub use database::Options;
+pub use datafile::DataFileMetadata;
+pub use error::Error;
 
 pub use database::new;
 pub use database::*;
 
-pub type ErrorResult<T> = Result<T, Box<dyn std::error::Error>>;
+pub type ErrorResult<T> = Result<T, Error>;
 
-pub mod tests;
\ No newline at end of file
+pub mod tests;
@@ -0,0 +1,50 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use log::*;
+
+use crate::error::Error;
+use crate::ErrorResult;
+
+/// LockFile is an advisory lock on the database directory, so only one process
+/// appends to and merges its data files. The lock is released when it is dropped.
+#[derive(Debug)]
+pub struct LockFile {
+    file: std::fs::File,
+    pub path: std::path::PathBuf,
+}
+
+impl LockFile {
+    pub fn acquire(base_dir: &Path) -> ErrorResult<LockFile> {
+        let path = base_dir.join(crate::config::LOCK_FILE_NAME);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        if let Err(err) = file.try_lock() {
+            return match err {
+                std::fs::TryLockError::WouldBlock => Err(Error::Locked {
+                    path: base_dir.to_path_buf(),
+                    lock_path: path,
+                }),
+                std::fs::TryLockError::Error(err) => Err(err.into()),
+            };
+        }
+
+        trace!("LockFile.acquire: locked '{}'", path.display());
+
+        Ok(LockFile { file, path })
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // the lock file itself stays, removing it could race with the next process locking it:
+        self.file.unlock().unwrap_or_default();
+        trace!("LockFile.drop: unlocked '{}'", self.path.display());
+    }
+}
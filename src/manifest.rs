@@ -0,0 +1,145 @@
+use std::io::Write;
+use std::path::Path;
+
+use log::*;
+
+use crate::error::Error;
+use crate::ErrorResult;
+
+/// MergeManifest records the intent of a merge: the merged data file replaces the listed
+/// data files. It is written once the merged file and its hints are on disk and removed
+/// once the replaced files are gone, so startup can finish a merge which got interrupted
+/// in between. Merges which never got a manifest are rolled back instead.
+///
+/// The manifest is a small text file, ie.
+/// ```text
+/// merged 1700000000000000000
+/// replaced 1690000000000000000
+/// replaced 1695000000000000000
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeManifest {
+    // id of the merged data file, None if there was no record left to write:
+    pub merged_id: Option<u128>,
+    pub replaced_ids: Vec<u128>,
+}
+
+impl MergeManifest {
+    /// write stores the manifest under a temporary name and renames it, so the manifest
+    /// is either complete or not there at all.
+    pub fn write(&self, base_dir: &Path) -> ErrorResult<()> {
+        let tmp_path = base_dir.join(crate::config::MERGE_MANIFEST_TMP_FILE_NAME);
+        let path = base_dir.join(crate::config::MERGE_MANIFEST_FILE_NAME);
+
+        let mut content = match self.merged_id {
+            Some(id) => format!("merged {}\n", id),
+            None => "merged -\n".to_owned(),
+        };
+        for id in &self.replaced_ids {
+            content.push_str(&format!("replaced {}\n", id));
+        }
+
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        drop(file);
+
+        std::fs::rename(&tmp_path, &path)?;
+        crate::utils::sync_dir(base_dir)?;
+
+        Ok(())
+    }
+
+    /// read returns the manifest of an interrupted merge, if there is one.
+    pub fn read(base_dir: &Path) -> ErrorResult<Option<MergeManifest>> {
+        let path = base_dir.join(crate::config::MERGE_MANIFEST_FILE_NAME);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let invalid = |reason: String| Error::InvalidFormat {
+            path: path.clone(),
+            reason,
+        };
+
+        let mut merged_id = None;
+        let mut replaced_ids = Vec::new();
+        let mut lines = content.lines();
+        match lines.next().and_then(|line| line.strip_prefix("merged ")) {
+            Some("-") => {}
+            Some(id) => {
+                merged_id = Some(
+                    id.parse()
+                        .map_err(|_| invalid(format!("invalid merged file id '{}'", id)))?,
+                )
+            }
+            None => return Err(invalid("missing merged file id".to_owned())),
+        }
+        for line in lines {
+            let id = line
+                .strip_prefix("replaced ")
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(|| invalid(format!("invalid line '{}'", line)))?;
+            replaced_ids.push(id);
+        }
+
+        Ok(Some(MergeManifest {
+            merged_id,
+            replaced_ids,
+        }))
+    }
+
+    /// install puts the merged file in place, under its data file name. Running it
+    /// again is fine, a file which is already in place is left alone.
+    pub fn install(&self, base_dir: &Path) -> ErrorResult<()> {
+        if let Some(merged_id) = self.merged_id {
+            let merged_path = base_dir.join(crate::config::merge_file_format(merged_id));
+            if merged_path.exists() {
+                let data_path = base_dir.join(crate::config::data_file_format(merged_id));
+                trace!(
+                    "MergeManifest.install: renaming '{}' to '{}'",
+                    merged_path.display(),
+                    data_path.display()
+                );
+                std::fs::rename(&merged_path, &data_path)?;
+                crate::utils::sync_dir(base_dir)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// finish removes the replaced data files with their hint files and then the manifest,
+    /// which completes the merge.
+    pub fn finish(&self, base_dir: &Path) -> ErrorResult<()> {
+        for id in &self.replaced_ids {
+            for path in [
+                base_dir.join(crate::config::data_file_format(*id)),
+                base_dir.join(crate::config::index_file_format(*id)),
+            ] {
+                match std::fs::remove_file(&path) {
+                    Ok(()) => trace!("MergeManifest.finish: removed '{}'", path.display()),
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+        crate::utils::sync_dir(base_dir)?;
+
+        std::fs::remove_file(base_dir.join(crate::config::MERGE_MANIFEST_FILE_NAME))?;
+        crate::utils::sync_dir(base_dir)?;
+
+        Ok(())
+    }
+
+    /// is_installable tells if the merged file is still there. A manifest without it
+    /// can't be finished, the replaced files are the only copy of their records then.
+    pub fn is_installable(&self, base_dir: &Path) -> bool {
+        self.merged_id.is_none_or(|id| {
+            base_dir.join(crate::config::merge_file_format(id)).exists()
+                || base_dir.join(crate::config::data_file_format(id)).exists()
+        })
+    }
+}
@@ -15,6 +15,27 @@ impl DatabaseTesting {
         let opts = crate::Options {
             base_dir: std::path::PathBuf::from(format!("./data/{}", db_name)),
             data_file_limit: max_datafile_size_bytes,
+            ..Default::default()
+        };
+
+        let _ = std::fs::remove_dir_all(&opts.base_dir);
+
+        let base_dir = opts.base_dir.to_owned();
+
+        let db = crate::new(opts).unwrap();
+
+        DatabaseTesting {
+            db,
+            base_dir,
+            cleanup_on_drop: true,
+        }
+    }
+
+    /// like new, but with custom options. The base_dir is replaced by './data/<db_name>':
+    pub fn new_with_options(db_name: String, options: crate::Options) -> DatabaseTesting {
+        let opts = crate::Options {
+            base_dir: std::path::PathBuf::from(format!("./data/{}", db_name)),
+            ..options
         };
 
         let _ = std::fs::remove_dir_all(&opts.base_dir);
@@ -35,33 +56,64 @@ impl DatabaseTesting {
     }
 
     pub fn open(db_name: String, max_datafile_size_bytes: u64) -> DatabaseTesting {
+        Self::try_open(db_name, max_datafile_size_bytes).unwrap()
+    }
+
+    pub fn try_open(
+        db_name: String,
+        max_datafile_size_bytes: u64,
+    ) -> crate::ErrorResult<DatabaseTesting> {
         std::env::set_var("RUST_TEST_THREADS", "1");
         // std::env::set_var("RUST_LOG", "bitcask");
 
         let opts = crate::Options {
             base_dir: std::path::PathBuf::from(format!("./data/{}", db_name)),
             data_file_limit: max_datafile_size_bytes,
+            ..Default::default()
         };
 
         let base_dir = opts.base_dir.to_owned();
 
-        let db = crate::new(opts).unwrap();
+        let db = crate::new(opts)?;
 
-        DatabaseTesting {
+        Ok(DatabaseTesting {
             db,
             base_dir,
             cleanup_on_drop: true,
-        }
+        })
+    }
+
+    /// opens an existing database read-only, its files are left as they are on drop:
+    pub fn try_open_read_only(db_name: String) -> crate::ErrorResult<DatabaseTesting> {
+        let opts = crate::Options {
+            base_dir: std::path::PathBuf::from(format!("./data/{}", db_name)),
+            read_only: true,
+            ..Default::default()
+        };
+
+        let base_dir = opts.base_dir.to_owned();
+
+        let db = crate::new(opts)?;
+
+        Ok(DatabaseTesting {
+            db,
+            base_dir,
+            cleanup_on_drop: false,
+        })
     }
 
     /// all = mutable/active + immutable data files:
     pub fn count_all_data_files(&self) -> usize {
-        self.glob_files("data.*").iter().count()
+        self.glob_files("data.*").len()
     }
 
     /// after compaction/merge, a index should be written:
     pub fn count_all_index_files(&self) -> usize {
-        self.glob_files("index.*").iter().count()
+        self.glob_files("index.*").len()
+    }
+
+    pub fn data_file_paths(&self) -> Vec<PathBuf> {
+        self.glob_files("data.*")
     }
 
     pub fn size_all_data_files(&self) -> usize {
@@ -73,7 +125,7 @@ impl DatabaseTesting {
             bytes += std::fs::metadata(entry).unwrap().len();
         }
 
-        (bytes as usize)
+        bytes as usize
     }
 
     fn glob_files(&self, glob_pattern: &'static str) -> Vec<PathBuf> {
@@ -84,7 +136,7 @@ impl DatabaseTesting {
 
         let mut entries: Vec<PathBuf> = glob_result.map(|x| x.unwrap()).collect();
 
-        entries.sort_by(|a, b| natord::compare(&a.to_str().unwrap(), &b.to_str().unwrap()));
+        entries.sort_by(|a, b| natord::compare(a.to_str().unwrap(), b.to_str().unwrap()));
         entries
     }
 }
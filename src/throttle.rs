@@ -0,0 +1,48 @@
+use std::time::{Duration, Instant};
+
+/// Throttle paces a stream of records to at most the given bytes and records per second,
+/// by sleeping whenever it got ahead of the allowed rate.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: Option<u64>,
+    records_per_sec: Option<u64>,
+    started_at: Instant,
+    bytes: u64,
+    records: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: Option<u64>, records_per_sec: Option<u64>) -> Throttle {
+        Throttle {
+            bytes_per_sec,
+            records_per_sec,
+            started_at: Instant::now(),
+            bytes: 0,
+            records: 0,
+        }
+    }
+
+    // longest sleep before the throttle checks whether it should stop waiting:
+    const SLICE: Duration = Duration::from_millis(10);
+
+    /// consume accounts for one record of `bytes` and waits until the rate allows it,
+    /// or until `stop` returns true. `stop` is checked every few milliseconds.
+    pub fn consume(&mut self, bytes: u64, stop: impl Fn() -> bool) {
+        self.bytes += bytes;
+        self.records += 1;
+
+        let due = |amount: u64, per_sec: Option<u64>| match per_sec {
+            Some(per_sec) if per_sec > 0 => Duration::from_secs_f64(amount as f64 / per_sec as f64),
+            _ => Duration::ZERO,
+        };
+        let due = due(self.bytes, self.bytes_per_sec).max(due(self.records, self.records_per_sec));
+
+        loop {
+            let elapsed = self.started_at.elapsed();
+            if due <= elapsed || stop() {
+                return;
+            }
+            std::thread::sleep((due - elapsed).min(Self::SLICE));
+        }
+    }
+}
@@ -5,14 +5,25 @@ pub fn time() -> u128 {
         .as_nanos()
 }
 
-pub fn extract_id_from_filename(
-    entry: &std::path::PathBuf,
-) -> Result<u128, Box<dyn std::error::Error>> {
+/// extract_id_from_filename returns the id of files in format <name>.<id>, ie. 'data.123':
+pub fn extract_id_from_filename(entry: &std::path::Path) -> crate::ErrorResult<u128> {
     entry
         .extension()
-        .ok_or_else(|| string_error::new_err("Missing extension (ie. not in format: data.<id>)"))?
-        .to_str()
-        .unwrap()
-        .parse()
-        .map_err(Into::into) // cast the FromStr error to dyn std::error::Error
+        .and_then(|extension| extension.to_str())
+        .and_then(|extension| extension.parse().ok())
+        .ok_or_else(|| crate::Error::InvalidFileName {
+            path: entry.to_path_buf(),
+        })
+}
+
+/// sync_dir makes renames, creations and deletions within a directory durable.
+#[cfg(unix)]
+pub fn sync_dir(path: &std::path::Path) -> std::io::Result<()> {
+    std::fs::File::open(path)?.sync_all()
+}
+
+// directories can't be opened (and don't need to be synced) on other platforms:
+#[cfg(not(unix))]
+pub fn sync_dir(_path: &std::path::Path) -> std::io::Result<()> {
+    Ok(())
 }
@@ -5,7 +5,7 @@ use bitcask::tests::common;
 
 #[test]
 fn writing_a_key_should_return_same_value() {
-    let mut db = common::DatabaseTesting::new("db1".to_owned(), ByteSize::b(1).as_u64());
+    let db = common::DatabaseTesting::new("db1".to_owned(), ByteSize::b(1).as_u64());
 
     db.write(b"name", b"Peter").unwrap();
 
@@ -32,7 +32,7 @@ fn writing_a_key_should_return_same_value() {
 
 #[test]
 fn updating_a_key_should_return_new_value() {
-    let mut db = common::DatabaseTesting::new("db2".to_owned(), ByteSize::b(1).as_u64());
+    let db = common::DatabaseTesting::new("db2".to_owned(), ByteSize::b(1).as_u64());
 
     db.write(b"name", b"Peter").unwrap();
     db.write(b"name", b"Susi").unwrap();
@@ -56,12 +56,13 @@ fn updating_a_key_should_return_new_value() {
     );
 
     let count_all_indices_files = db.count_all_index_files();
-    assert_eq!(0, count_all_indices_files, "Number of indices files");
+    // the rotated data file got a hint file:
+    assert_eq!(1, count_all_indices_files, "Number of indices files");
 }
 
 #[test]
 fn compaction_should_delete_duplicate_values() {
-    let mut db = common::DatabaseTesting::new("db3".to_owned(), ByteSize::b(1).as_u64());
+    let db = common::DatabaseTesting::new("db3".to_owned(), ByteSize::b(1).as_u64());
 
     db.write(b"name", b"Peter").unwrap();
     let before_size_data_files = db.size_all_data_files();
@@ -85,27 +86,29 @@ fn compaction_should_delete_duplicate_values() {
     );
     assert_eq!(1, stats.num_keys, "Number of keys");
 
+    // the merged data file + the empty active one, which the next write goes to:
     let count_all_data_files = db.count_all_data_files();
     assert_eq!(
-        1, count_all_data_files,
+        2, count_all_data_files,
         "Number of mutable + immutable data files"
     );
 
     assert_eq!(
-        before_size_data_files, after_size_data_files,
-        "Writing 1 entry should be the size after compaction"
+        before_size_data_files + 38,
+        after_size_data_files,
+        "Writing 1 entry should be the size after compaction (+ header of the active file)"
     );
 
     let count_all_indices_files = db.count_all_index_files();
     assert_eq!(1, count_all_indices_files, "Number of indices files");
 
-    let mut df = db.get_datafile_at(0);
-    assert_eq!("00000000 | S | name | Peter", df.inspect(false));
+    let mut df = db.get_datafile_at(0).unwrap();
+    assert_eq!("00000038 | S | name | Peter", df.inspect(false));
 }
 
 #[test]
 fn compacting_multiple_times_should_delete_duplicate_values() {
-    let mut db = common::DatabaseTesting::new("db4".to_owned(), ByteSize::b(1).as_u64());
+    let db = common::DatabaseTesting::new("db4".to_owned(), ByteSize::b(1).as_u64());
 
     db.write(b"name", b"Peter").unwrap();
     let before_size_data_files = db.size_all_data_files();
@@ -146,12 +149,13 @@ fn compacting_multiple_times_should_delete_duplicate_values() {
     );
 
     let count_all_indices_files = db.count_all_index_files();
-    assert_eq!(1, count_all_indices_files, "Number of indices files");
+    // every key lives in the active data file, so there is no merged file to index:
+    assert_eq!(0, count_all_indices_files, "Number of indices files");
 }
 
 #[test]
 fn compacting_multiple_times_should_old_delete_duplicate_values_multiple_values() {
-    let mut db = common::DatabaseTesting::new("db5".to_owned(), ByteSize::b(1).as_u64());
+    let db = common::DatabaseTesting::new("db5".to_owned(), ByteSize::b(1).as_u64());
 
     db.write(b"name", b"Peter").unwrap();
     let before_size_data_files = db.size_all_data_files();
@@ -189,9 +193,8 @@ fn compacting_multiple_times_should_old_delete_duplicate_values_multiple_values(
         "Number of mutable + immutable data files"
     );
 
-    assert_eq!(
-        true,
-        (before_size_data_files < after_size_data_files),
+    assert!(
+        before_size_data_files < after_size_data_files,
         "the new compacted data file should not be null"
     );
 
@@ -210,33 +213,33 @@ fn compacting_multiple_times_should_old_delete_duplicate_values_multiple_values(
     // The current data file (which is the only mutable datafile) should have
     // exactly 1 entry and the reason is that after the first write it will
     // create a new datafile and switch the writer to the new datafile:
-    let mut db0 = db.get_current_datafile();
-    assert_eq!("00000000 | S | name.1009 | Susi 9", db0.inspect(false));
+    let mut db0 = db.get_current_datafile().unwrap();
+    assert_eq!("00000038 | S | name.1009 | Susi 9", db0.inspect(false));
     // println!(">>> {}", db0.inspect(true));
 
     let expected = r#"
-00000000 | S | name | Peter
-00000041 | S | name.0 | Susi 0
-00000085 | S | name.1 | Susi 1
-00000129 | S | name.1000 | Susi 0
-00000176 | S | name.1001 | Susi 1
-00000223 | S | name.1002 | Susi 2
-00000270 | S | name.1003 | Susi 3
-00000317 | S | name.1004 | Susi 4
-00000364 | S | name.1005 | Susi 5
-00000411 | S | name.1006 | Susi 6
-00000458 | S | name.1007 | Susi 7
-00000505 | S | name.1008 | Susi 8
-00000552 | S | name.2 | Susi 2
-00000596 | S | name.3 | Susi 3
-00000640 | S | name.4 | Susi 4
-00000684 | S | name.5 | Susi 5
-00000728 | S | name.6 | Susi 6
-00000772 | S | name.7 | Susi 7
-00000816 | S | name.8 | Susi 8
-00000860 | S | name.9 | Susi 9"#;
-
-    let mut db1 = db.get_datafile_at(0);
+00000038 | S | name | Peter
+00000087 | S | name.0 | Susi 0
+00000139 | S | name.1 | Susi 1
+00000191 | S | name.1000 | Susi 0
+00000246 | S | name.1001 | Susi 1
+00000301 | S | name.1002 | Susi 2
+00000356 | S | name.1003 | Susi 3
+00000411 | S | name.1004 | Susi 4
+00000466 | S | name.1005 | Susi 5
+00000521 | S | name.1006 | Susi 6
+00000576 | S | name.1007 | Susi 7
+00000631 | S | name.1008 | Susi 8
+00000686 | S | name.2 | Susi 2
+00000738 | S | name.3 | Susi 3
+00000790 | S | name.4 | Susi 4
+00000842 | S | name.5 | Susi 5
+00000894 | S | name.6 | Susi 6
+00000946 | S | name.7 | Susi 7
+00000998 | S | name.8 | Susi 8
+00001050 | S | name.9 | Susi 9"#;
+
+    let mut db1 = db.get_datafile_at(0).unwrap();
     assert_eq!(expected.trim(), db1.inspect(false));
     // println!(">>> {}", db1.inspect(true));
 }
@@ -272,21 +275,21 @@ fn append_only_log_should_also_write_deletes() {
         "Number of mutable + immutable data files"
     );
 
-    assert_eq!(
-        true,
-        (before_size_data_files < after_size_data_files),
+    assert!(
+        before_size_data_files < after_size_data_files,
         "after deleting, the file gets bigger (due to append only system)"
     );
 
     let count_all_indices_files = db.count_all_index_files();
-    assert_eq!(0, count_all_indices_files, "Number of indices files");
+    // the data file of the previous run got its hint file on startup:
+    assert_eq!(1, count_all_indices_files, "Number of indices files");
 
     let expected = r#"
-00000000 | S | name | Peter
-00000041 | D | name | %_%_%_%<!(R|E|M|O|V|E|D)!>%_%_%_%_
+00000038 | S | name | Peter
+00000087 | D | name
 "#;
 
-    let mut db0 = db.get_datafile_at(0);
+    let mut db0 = db.get_datafile_at(0).unwrap();
     assert_eq!(expected.trim(), db0.inspect(false));
     // println!(">>> {}", db0.inspect(true));
 
@@ -301,11 +304,8 @@ fn append_only_log_should_also_write_deletes() {
 
     // current entry still has the 'REMOVED' tombstone,
     // because we didn't rewrite that yet (it's still "active"):
-    let mut db1 = db.get_current_datafile();
-    assert_eq!(
-        "00000000 | D | name | %_%_%_%<!(R|E|M|O|V|E|D)!>%_%_%_%_",
-        db1.inspect(false)
-    );
+    let mut db1 = db.get_current_datafile().unwrap();
+    assert_eq!("00000038 | D | name", db1.inspect(false));
     // println!(">>> {}", db1.inspect(true));
 
     // lets trigger only writes now. Checking if the db works correctly after some removals:
@@ -321,14 +321,1569 @@ fn append_only_log_should_also_write_deletes() {
     db.write(b"test", b"123").unwrap();
 
     let expected = r#"
-00000000 | S | name1 | Peter
-00000042 | S | name2 | Peter
-00000084 | S | name3 | Peter
-00000126 | S | name4 | Peter
-00000168 | S | name5 | Peter
+00000038 | S | name1 | Peter
+00000088 | S | name2 | Peter
+00000138 | S | name3 | Peter
+00000188 | S | name4 | Peter
+00000238 | S | name5 | Peter
     "#;
 
-    let mut db2 = db.get_datafile_at(0);
+    let mut db2 = db.get_datafile_at(0).unwrap();
     assert_eq!(expected.trim(), db2.inspect(false));
     // println!(">>> {}", db2.inspect(true));
 }
+
+#[test]
+fn corrupted_records_should_be_detected() {
+    let mut db = common::DatabaseTesting::new("db7".to_owned(), ByteSize::mb(1).as_u64());
+
+    db.write(b"name", b"Peter").unwrap();
+    db.sync().unwrap();
+
+    // flip the last byte of the value:
+    let path = db.data_file_paths().pop().unwrap();
+    let mut bytes = std::fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&path, bytes).unwrap();
+
+    let err = db.read(b"name").unwrap_err();
+    assert!(
+        matches!(err, bitcask::Error::Corruption { offset: 38, .. }),
+        "{}",
+        err
+    );
+
+    db.disable_cleanup();
+    drop(db);
+
+    let err = common::DatabaseTesting::try_open("db7".to_owned(), ByteSize::mb(1).as_u64())
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err.root(), bitcask::Error::Corruption { .. }),
+        "{}",
+        err
+    );
+
+    let _ = std::fs::remove_dir_all("./data/db7");
+}
+
+#[test]
+fn unknown_files_and_versions_should_be_handled_on_startup() {
+    let mut db = common::DatabaseTesting::new("db8".to_owned(), ByteSize::mb(1).as_u64());
+
+    db.write(b"name", b"Peter").unwrap();
+    db.disable_cleanup();
+    drop(db);
+
+    // a stray file which only happens to match 'data.*' is ignored:
+    std::fs::write("./data/db8/data.foo", b"not a data file").unwrap();
+
+    let mut db = common::DatabaseTesting::open("db8".to_owned(), ByteSize::mb(1).as_u64());
+    assert_eq!(b"Peter".to_vec(), db.read(b"name").unwrap());
+
+    // files written by a newer format version are rejected:
+    let path = db.data_file_paths().remove(0);
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[4..6].copy_from_slice(&99u16.to_le_bytes());
+    std::fs::write(&path, bytes).unwrap();
+
+    db.disable_cleanup();
+    drop(db);
+
+    let err = common::DatabaseTesting::try_open("db8".to_owned(), ByteSize::mb(1).as_u64())
+        .err()
+        .unwrap();
+    assert!(
+        matches!(
+            err.root(),
+            bitcask::Error::UnsupportedVersion { version: 99, .. }
+        ),
+        "{}",
+        err
+    );
+
+    let _ = std::fs::remove_dir_all("./data/db8");
+}
+
+#[test]
+fn any_value_can_be_stored_including_the_old_tombstone() {
+    let mut db = common::DatabaseTesting::new("db9".to_owned(), ByteSize::mb(1).as_u64());
+
+    let old_tombstone = b"%_%_%_%<!(R|E|M|O|V|E|D)!>%_%_%_%_";
+    db.write(b"name", old_tombstone).unwrap();
+    db.disable_cleanup();
+    drop(db);
+
+    let db = common::DatabaseTesting::open("db9".to_owned(), ByteSize::mb(1).as_u64());
+    assert_eq!(old_tombstone.to_vec(), db.read(b"name").unwrap());
+}
+
+#[test]
+fn baseline_files_should_still_be_readable() {
+    // files without a header, records without a checksum and deletes as a tombstone value:
+    fn v0_record(timestamp: u128, key: &[u8], value: &[u8]) -> Vec<u8> {
+        bincode::serialize(&(timestamp, key.to_vec(), value.to_vec())).unwrap()
+    }
+
+    let tombstone = b"%_%_%_%<!(R|E|M|O|V|E|D)!>%_%_%_%_";
+
+    let _ = std::fs::remove_dir_all("./data/db10");
+    std::fs::create_dir_all("./data/db10").unwrap();
+
+    let mut bytes = v0_record(1, b"name", b"Peter");
+    bytes.extend(v0_record(2, b"gone", b"Susi"));
+    bytes.extend(v0_record(3, b"gone", tombstone));
+    std::fs::write("./data/db10/data.1", bytes).unwrap();
+
+    // a merged file with its hint file:
+    std::fs::write("./data/db10/data.2", v0_record(4, b"city", b"Berlin")).unwrap();
+    let hint = bincode::serialize(&(b"city".to_vec(), 2u128, 0u64, 4u128)).unwrap();
+    std::fs::write("./data/db10/index.2", hint).unwrap();
+
+    // smaller than a header:
+    std::fs::write("./data/db10/data.3", v0_record(5, b"a", b"b")).unwrap();
+
+    let check = |db: &common::DatabaseTesting| {
+        assert_eq!(b"Peter".to_vec(), db.read(b"name").unwrap());
+        assert_eq!(b"Berlin".to_vec(), db.read(b"city").unwrap());
+        assert_eq!(b"b".to_vec(), db.read(b"a").unwrap());
+        assert!(db.read(b"gone").is_err());
+        assert_eq!(3, db.stats().num_keys, "Number of keys");
+    };
+
+    let mut db = common::DatabaseTesting::open("db10".to_owned(), ByteSize::mb(1).as_u64());
+    check(&db);
+    db.disable_cleanup();
+    drop(db);
+
+    let db = common::DatabaseTesting::open("db10".to_owned(), ByteSize::mb(1).as_u64());
+    check(&db);
+    db.merge().unwrap();
+    check(&db);
+}
+
+#[test]
+fn torn_writes_should_be_cut_off_on_startup() {
+    let mut db = common::DatabaseTesting::new("db11".to_owned(), ByteSize::mb(1).as_u64());
+
+    db.write(b"name", b"Peter").unwrap();
+    db.write(b"age", b"20").unwrap();
+    db.disable_cleanup();
+
+    let path = db.data_file_paths().pop().unwrap();
+    drop(db);
+
+    // simulate a crash in the middle of writing a record:
+    let mut bytes = std::fs::read(&path).unwrap();
+    let len = bytes.len();
+    let partial_record = bytes[38..58].to_vec();
+    bytes.extend_from_slice(&partial_record);
+    std::fs::write(&path, bytes).unwrap();
+
+    let db = common::DatabaseTesting::open("db11".to_owned(), ByteSize::mb(1).as_u64());
+    assert_eq!(b"Peter".to_vec(), db.read(b"name").unwrap());
+    assert_eq!(b"20".to_vec(), db.read(b"age").unwrap());
+    assert_eq!(2, db.stats().num_keys, "Number of keys");
+
+    assert_eq!(len as u64, std::fs::metadata(&path).unwrap().len());
+
+    let file_id = path.extension().unwrap().to_str().unwrap();
+    let quarantined = std::fs::read(format!("./data/db11/quarantine.{}", file_id)).unwrap();
+    assert_eq!(20, quarantined.len());
+}
+
+#[test]
+fn rotated_data_files_should_get_hint_files() {
+    let mut db = common::DatabaseTesting::new("db12".to_owned(), ByteSize::b(1).as_u64());
+
+    db.write(b"a", b"1").unwrap();
+    db.write(b"b", b"2").unwrap(); // <-- rotates
+    db.remove(b"a").unwrap();
+    db.write(b"c", b"3").unwrap(); // <-- rotates
+
+    assert_eq!(2, db.stats().num_immutable_datafiles);
+    assert_eq!(2, db.count_all_index_files(), "Number of indices files");
+
+    db.disable_cleanup();
+    let first_data_file = db.data_file_paths().remove(0);
+    drop(db);
+
+    // startup must only read the hint files, so the records themselves are never looked at:
+    let mut bytes = std::fs::read(&first_data_file).unwrap();
+    for byte in bytes.iter_mut().skip(38) {
+        *byte = 0xff;
+    }
+    std::fs::write(&first_data_file, bytes).unwrap();
+
+    let db = common::DatabaseTesting::open("db12".to_owned(), ByteSize::b(1).as_u64());
+    assert_eq!(2, db.stats().num_keys, "Number of keys");
+    assert!(db.read(b"a").is_err());
+    assert_eq!(b"3".to_vec(), db.read(b"c").unwrap());
+}
+
+#[test]
+fn write_batches_should_be_applied_all_or_nothing() {
+    let mut db = common::DatabaseTesting::new("db13".to_owned(), ByteSize::mb(1).as_u64());
+
+    db.write(b"name", b"Peter").unwrap();
+
+    let mut batch = bitcask::WriteBatch::new();
+    batch.put(b"a", b"1").put(b"b", b"2").remove(b"name");
+    db.write_batch(&batch).unwrap();
+
+    assert_eq!(b"1".to_vec(), db.read(b"a").unwrap());
+    assert_eq!(b"2".to_vec(), db.read(b"b").unwrap());
+    assert!(db.read(b"name").is_err());
+
+    let expected = r#"
+00000038 | S | name | Peter
+00000087 | B | 3
+00000135 | S | a | 1
+00000177 | S | b | 2
+00000219 | D | name
+"#;
+    let mut df = db.get_current_datafile().unwrap();
+    assert_eq!(expected.trim(), df.inspect(false));
+
+    // a second batch whose last record never made it to disk:
+    let mut batch = bitcask::WriteBatch::new();
+    batch.put(b"c", b"3").remove(b"a");
+    db.write_batch(&batch).unwrap();
+
+    let path = db.data_file_paths().pop().unwrap();
+    let last_record_offset: u64 = db
+        .get_current_datafile()
+        .unwrap()
+        .inspect(false)
+        .lines()
+        .last()
+        .unwrap()[..8]
+        .parse()
+        .unwrap();
+
+    db.disable_cleanup();
+    drop(db);
+
+    let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(last_record_offset).unwrap();
+    drop(file);
+
+    let db = common::DatabaseTesting::open("db13".to_owned(), ByteSize::mb(1).as_u64());
+    assert_eq!(2, db.stats().num_keys, "Number of keys");
+    assert_eq!(b"1".to_vec(), db.read(b"a").unwrap());
+    assert_eq!(b"2".to_vec(), db.read(b"b").unwrap());
+    assert!(db.read(b"c").is_err());
+}
+
+#[test]
+fn database_handles_should_be_shared_between_threads() {
+    let db = common::DatabaseTesting::new("db14".to_owned(), ByteSize::kb(1).as_u64());
+
+    db.write(b"shared", b"0").unwrap();
+
+    let writers: Vec<_> = (0..4)
+        .map(|thread| {
+            let db: bitcask::Database = (*db).clone();
+            std::thread::spawn(move || {
+                for n in 0..100 {
+                    let key = format!("thread:{}:{}", thread, n);
+                    db.write(key.as_bytes(), key.as_bytes()).unwrap();
+                    db.write(b"shared", key.as_bytes()).unwrap();
+                }
+            })
+        })
+        .collect();
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let db: bitcask::Database = (*db).clone();
+            std::thread::spawn(move || {
+                for _ in 0..100 {
+                    assert!(!db.read(b"shared").unwrap().is_empty());
+                    assert!(!db.read_cache(b"shared").unwrap().is_empty());
+                }
+            })
+        })
+        .collect();
+
+    for handle in writers.into_iter().chain(readers) {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(401, db.stats().num_keys, "Number of keys");
+    assert!(db.stats().num_immutable_datafiles > 0);
+    for thread in 0..4 {
+        let key = format!("thread:{}:99", thread);
+        assert_eq!(
+            key.as_bytes().to_vec(),
+            db.read_cache(key.as_bytes()).unwrap()
+        );
+    }
+    assert_eq!(
+        vec![b"thread:3:98".to_vec(), b"thread:3:99".to_vec()],
+        db.keys_range_min(b"thread:3:98")
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>()
+    );
+
+    db.merge().unwrap();
+    assert_eq!(401, db.stats().num_keys, "Number of keys");
+    assert_eq!(b"thread:0:0".to_vec(), db.read(b"thread:0:0").unwrap());
+}
+
+#[test]
+fn base_dir_should_be_locked_while_the_database_is_open() {
+    let mut db = common::DatabaseTesting::new("db15".to_owned(), ByteSize::mb(1).as_u64());
+    db.write(b"name", b"Peter").unwrap();
+
+    let err = common::DatabaseTesting::try_open("db15".to_owned(), ByteSize::mb(1).as_u64())
+        .err()
+        .expect("second open should fail");
+    assert!(matches!(err, bitcask::Error::Locked { .. }));
+
+    // a closed database gives up the lock, but can still be read:
+    db.close().unwrap();
+    assert_eq!(b"Peter".to_vec(), db.read(b"name").unwrap());
+    let err = db.write(b"name", b"Paul").unwrap_err();
+    assert!(matches!(err, bitcask::Error::Closed));
+
+    let mut reopened = common::DatabaseTesting::open("db15".to_owned(), ByteSize::mb(1).as_u64());
+    reopened.disable_cleanup();
+    reopened.write(b"name", b"Paul").unwrap();
+    drop(reopened);
+
+    // dropping releases the lock as well:
+    db.disable_cleanup();
+    drop(db);
+    let db = common::DatabaseTesting::open("db15".to_owned(), ByteSize::mb(1).as_u64());
+    assert_eq!(b"Paul".to_vec(), db.read(b"name").unwrap());
+}
+
+#[test]
+fn read_only_databases_should_not_touch_any_file() {
+    let db = common::DatabaseTesting::new("db16".to_owned(), ByteSize::b(1).as_u64());
+    db.write(b"name", b"Peter").unwrap();
+    db.write(b"name", b"Paul").unwrap();
+    db.write(b"age", b"42").unwrap();
+    db.remove(b"age").unwrap();
+
+    let files_with_sizes = || -> Vec<(std::path::PathBuf, u64)> {
+        let mut files: Vec<_> = std::fs::read_dir("./data/db16")
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.path(), entry.metadata().unwrap().len())
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files_with_sizes();
+
+    // the writer still holds the lock, the reader does not need it:
+    let reader = common::DatabaseTesting::try_open_read_only("db16".to_owned()).unwrap();
+    assert_eq!(b"Paul".to_vec(), reader.read(b"name").unwrap());
+    assert_eq!(b"Paul".to_vec(), reader.read_cache(b"name").unwrap());
+    assert!(reader.read(b"age").is_err());
+    assert_eq!(1, reader.stats().num_keys, "Number of keys");
+
+    let is_read_only =
+        |result: bitcask::ErrorResult<()>| matches!(result, Err(bitcask::Error::ReadOnly));
+    assert!(is_read_only(reader.write(b"name", b"Susi")));
+    assert!(is_read_only(reader.remove(b"name")));
+    assert!(is_read_only(reader.merge().map(|_| ())));
+    assert!(is_read_only(
+        reader.write_batch(bitcask::WriteBatch::new().put(b"name", b"Susi"))
+    ));
+    reader.close().unwrap();
+    drop(reader);
+
+    assert_eq!(before, files_with_sizes());
+
+    assert!(common::DatabaseTesting::try_open_read_only("db16-missing".to_owned()).is_err());
+    assert!(!std::path::Path::new("./data/db16-missing").exists());
+}
+
+#[test]
+fn every_sync_policy_should_keep_the_writes() {
+    use bitcask::SyncPolicy;
+
+    let policies = [
+        SyncPolicy::Always,
+        SyncPolicy::IntervalMs(1),
+        SyncPolicy::IntervalMs(0),
+        SyncPolicy::Bytes(100),
+        SyncPolicy::Never,
+    ];
+
+    for policy in policies.iter() {
+        let options = bitcask::Options {
+            data_file_limit: ByteSize::b(200).as_u64(),
+            sync_policy: *policy,
+            ..Default::default()
+        };
+        let mut db = common::DatabaseTesting::new_with_options("db17".to_owned(), options);
+
+        for n in 0..20 {
+            let key = format!("name:{}", n);
+            db.write(key.as_bytes(), b"Peter").unwrap();
+        }
+        db.remove(b"name:0").unwrap();
+        db.write_batch(bitcask::WriteBatch::new().put(b"name:0", b"Paul"))
+            .unwrap();
+
+        // give the background thread a chance to run:
+        std::thread::sleep(std::time::Duration::from_millis(5));
+
+        db.disable_cleanup();
+        drop(db);
+
+        let db = common::DatabaseTesting::open("db17".to_owned(), ByteSize::b(200).as_u64());
+        assert_eq!(20, db.stats().num_keys, "Number of keys with {:?}", policy);
+        assert_eq!(b"Paul".to_vec(), db.read(b"name:0").unwrap());
+        assert_eq!(b"Peter".to_vec(), db.read(b"name:19").unwrap());
+    }
+}
+
+#[test]
+fn concurrent_writes_should_be_committed_together() {
+    let options = bitcask::Options {
+        data_file_limit: ByteSize::mb(1).as_u64(),
+        sync_policy: bitcask::SyncPolicy::Always,
+        ..Default::default()
+    };
+    let mut db = common::DatabaseTesting::new_with_options("db18".to_owned(), options);
+
+    let writers: Vec<_> = (0..8)
+        .map(|thread| {
+            let db: bitcask::Database = (*db).clone();
+            std::thread::spawn(move || {
+                for n in 0..50 {
+                    let key = format!("key:{}:{}", thread, n);
+                    db.write(key.as_bytes(), b"value").unwrap();
+
+                    let mut batch = bitcask::WriteBatch::new();
+                    batch
+                        .put(format!("batch:{}:{}:a", thread, n).as_bytes(), b"1")
+                        .put(format!("batch:{}:{}:b", thread, n).as_bytes(), b"2");
+                    db.write_batch(&batch).unwrap();
+
+                    if n % 2 == 0 {
+                        db.remove(key.as_bytes()).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in writers {
+        handle.join().unwrap();
+    }
+
+    // the records of a batch are never interleaved with the ones of other writers:
+    let records = db.get_current_datafile().unwrap().inspect(false);
+    let lines: Vec<&str> = records.lines().collect();
+    for (n, line) in lines.iter().enumerate() {
+        if line.ends_with("| B | 2") {
+            let prefix = lines[n + 1]
+                .split(" | ")
+                .nth(2)
+                .unwrap()
+                .trim_end_matches(":a");
+            assert!(lines[n + 2].contains(&format!("{}:b", prefix)));
+        }
+    }
+
+    db.disable_cleanup();
+    drop(db);
+
+    let db = common::DatabaseTesting::open("db18".to_owned(), ByteSize::mb(1).as_u64());
+    assert_eq!(8 * 25 + 8 * 100, db.stats().num_keys, "Number of keys");
+    assert_eq!(b"value".to_vec(), db.read(b"key:7:49").unwrap());
+    assert!(db.read(b"key:7:48").is_err());
+    assert_eq!(b"2".to_vec(), db.read(b"batch:3:10:b").unwrap());
+}
+
+#[test]
+fn keys_with_a_ttl_should_expire() {
+    use std::time::Duration;
+
+    let db = common::DatabaseTesting::new("db19".to_owned(), ByteSize::b(1).as_u64());
+
+    db.write_with_ttl(b"session:a", b"1", Duration::from_millis(500))
+        .unwrap();
+    db.write_with_ttl(b"session:b", b"2", Duration::from_secs(3600))
+        .unwrap();
+    db.write(b"name", b"Peter").unwrap();
+    assert_eq!(b"1".to_vec(), db.read(b"session:a").unwrap());
+
+    // the expiry comes back from the hint files of the rotated data files:
+    let mut db = db;
+    db.disable_cleanup();
+    drop(db);
+    let db = common::DatabaseTesting::open("db19".to_owned(), ByteSize::b(1).as_u64());
+    assert_eq!(2, db.count_all_index_files());
+    assert_eq!(b"1".to_vec(), db.read(b"session:a").unwrap());
+
+    std::thread::sleep(Duration::from_millis(600));
+
+    assert!(db.read(b"session:a").is_err());
+    assert!(db.read_cache(b"session:a").is_err());
+    assert_eq!(b"2".to_vec(), db.read_cache(b"session:b").unwrap());
+    assert_eq!(
+        vec![b"name".to_vec(), b"session:b".to_vec()],
+        db.keys().collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![b"session:b".to_vec()],
+        db.keys_range(b"session:", b"session:z")
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(2, db.stats().num_keys, "Number of keys");
+
+    // merge drops the expired key and keeps the expiry of the other one:
+    db.merge().unwrap();
+    let merged = db.get_datafile_at(0).unwrap().inspect(false);
+    assert!(!merged.contains("session:a"));
+    assert!(merged.contains("| T | session:b | 2 |"));
+    assert_eq!(b"2".to_vec(), db.read(b"session:b").unwrap());
+    assert_eq!(2, db.stats().num_keys, "Number of keys");
+}
+
+#[test]
+fn errors_should_tell_a_missing_key_from_a_failure() {
+    let db = common::DatabaseTesting::new("db20".to_owned(), ByteSize::b(1).as_u64());
+    db.write(b"name", b"Peter").unwrap();
+    db.write(b"age", b"42").unwrap();
+
+    match db.read(b"unknown") {
+        Err(bitcask::Error::NotFound { key }) => assert_eq!(b"unknown".to_vec(), key),
+        other => panic!("expected NotFound, got {:?}", other),
+    }
+
+    // the data file of a key disappeared:
+    let path = db.data_file_paths().remove(0);
+    std::fs::remove_file(path).unwrap();
+    match db.read(b"name") {
+        Err(bitcask::Error::Io { source }) => {
+            assert_eq!(std::io::ErrorKind::NotFound, source.kind())
+        }
+        other => panic!("expected Io, got {:?}", other),
+    }
+}
+
+#[test]
+fn odd_files_should_fail_the_startup_with_their_path_or_be_skipped() {
+    let mut db = common::DatabaseTesting::new("db21".to_owned(), ByteSize::b(1).as_u64());
+    db.write(b"name", b"Peter").unwrap();
+    db.write(b"age", b"42").unwrap();
+    db.disable_cleanup();
+    drop(db);
+
+    // files which look like ours are skipped by default and left alone:
+    let base_dir = std::path::PathBuf::from("./data/db21");
+    std::fs::write(base_dir.join("data.foo"), b"not a data file").unwrap();
+    std::fs::write(base_dir.join("index.bak"), b"not a hint file").unwrap();
+
+    let mut db = common::DatabaseTesting::open("db21".to_owned(), ByteSize::b(1).as_u64());
+    assert_eq!(b"Peter".to_vec(), db.read(b"name").unwrap());
+    db.merge().unwrap();
+    assert!(base_dir.join("data.foo").exists());
+    assert!(base_dir.join("index.bak").exists());
+    db.disable_cleanup();
+    drop(db);
+
+    let err = bitcask::new(bitcask::Options {
+        base_dir: base_dir.clone(),
+        unknown_files: bitcask::UnknownFilePolicy::Fail,
+        ..Default::default()
+    })
+    .err()
+    .unwrap();
+    match err {
+        bitcask::Error::InvalidFileName { path } => {
+            assert!(path.ends_with("data.foo") || path.ends_with("index.bak"))
+        }
+        other => panic!("expected InvalidFileName, got {:?}", other),
+    }
+    std::fs::remove_file(base_dir.join("data.foo")).unwrap();
+    std::fs::remove_file(base_dir.join("index.bak")).unwrap();
+
+    // a cut off hint file is reported together with its path:
+    let mut db = common::DatabaseTesting::open("db21".to_owned(), ByteSize::b(1).as_u64());
+    db.write(b"city", b"Berlin").unwrap();
+    db.disable_cleanup();
+    drop(db);
+
+    let index_path = glob::glob("./data/db21/index.*")
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    let index = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&index_path)
+        .unwrap();
+    index.set_len(index.metadata().unwrap().len() - 3).unwrap();
+
+    let err = common::DatabaseTesting::try_open("db21".to_owned(), ByteSize::b(1).as_u64())
+        .err()
+        .unwrap();
+    match err {
+        bitcask::Error::KeyDirFill { source, .. } => match *source {
+            bitcask::Error::FileLoad { path, source } => {
+                assert!(path.to_string_lossy().contains("data."));
+                assert!(
+                    matches!(&*source, bitcask::Error::FileLoad { path, .. } if path.file_name() == index_path.file_name()),
+                    "{}",
+                    source
+                );
+            }
+            other => panic!("expected FileLoad, got {:?}", other),
+        },
+        other => panic!("expected KeyDirFill, got {:?}", other),
+    }
+
+    let _ = std::fs::remove_dir_all(&base_dir);
+}
+
+#[test]
+fn compressed_and_uncompressed_values_should_be_readable() {
+    let json = br#"{"name": "Peter", "city": "Berlin", "tags": ["a", "b", "c"]}"#.repeat(50);
+
+    let mut db = common::DatabaseTesting::new_with_options(
+        "db22".to_owned(),
+        bitcask::Options {
+            compression: bitcask::Compression::Lz4,
+            ..Default::default()
+        },
+    );
+    db.write(b"lz4", &json).unwrap();
+    // values below the threshold are stored as they are:
+    db.write(b"small", b"Peter").unwrap();
+    assert!(db.size_all_data_files() < json.len());
+    assert_eq!(json, db.read(b"lz4").unwrap());
+    db.disable_cleanup();
+    drop(db);
+
+    // the codec is recorded per record, whatever the options say now:
+    let base_dir = std::path::PathBuf::from("./data/db22");
+    let open = |compression| {
+        bitcask::new(bitcask::Options {
+            base_dir: base_dir.clone(),
+            data_file_limit: 1,
+            compression,
+            ..Default::default()
+        })
+        .unwrap()
+    };
+
+    let db = open(bitcask::Compression::None);
+    assert_eq!(json, db.read(b"lz4").unwrap());
+    db.write(b"plain", &json).unwrap();
+    drop(db);
+
+    let db = open(bitcask::Compression::Zstd(3));
+    db.write(b"zstd", &json).unwrap();
+    db.write(b"other", b"value").unwrap();
+    for key in [&b"lz4"[..], b"plain", b"zstd"].iter() {
+        assert_eq!(json, db.read(key).unwrap());
+        assert_eq!(json, db.read_cache(key).unwrap());
+    }
+    assert_eq!(b"Peter".to_vec(), db.read(b"small").unwrap());
+
+    // merge rewrites every value with the current codec:
+    db.merge().unwrap();
+    for key in [&b"lz4"[..], b"plain", b"zstd"].iter() {
+        assert_eq!(json, db.read(key).unwrap());
+    }
+    assert!(db
+        .get_datafile_at(0)
+        .unwrap()
+        .inspect(false)
+        .contains(&format!("| S | plain | {}", String::from_utf8_lossy(&json))));
+    drop(db);
+
+    let _ = std::fs::remove_dir_all(&base_dir);
+}
+
+#[test]
+fn encrypted_databases_should_need_their_key_and_support_rotation() {
+    let base_dir = std::path::PathBuf::from("./data/db23");
+    let _ = std::fs::remove_dir_all(&base_dir);
+    let options = |key: Option<[u8; 32]>| bitcask::Options {
+        base_dir: base_dir.clone(),
+        data_file_limit: 1,
+        encryption_key: key.map(bitcask::EncryptionKey),
+        ..Default::default()
+    };
+    let contains_secret = || {
+        std::fs::read_dir(&base_dir).unwrap().any(|entry| {
+            let bytes = std::fs::read(entry.unwrap().path()).unwrap();
+            bytes.windows(6).any(|window| window == b"secret")
+        })
+    };
+
+    let db = bitcask::new(options(Some([1; 32]))).unwrap();
+    db.write(b"name", b"Peter secret").unwrap();
+    db.write(b"city", b"Berlin secret").unwrap();
+    db.write(b"name", b"Paul secret").unwrap();
+    db.remove(b"city").unwrap();
+    db.write(b"age", b"42 secret").unwrap();
+    db.merge().unwrap();
+    assert_eq!(b"Paul secret".to_vec(), db.read(b"name").unwrap());
+    drop(db);
+    assert!(!contains_secret());
+
+    let err = bitcask::new(options(None)).err().unwrap();
+    assert!(matches!(
+        err.root(),
+        bitcask::Error::MissingEncryptionKey { .. }
+    ));
+    let err = bitcask::new(options(Some([2; 32]))).err().unwrap();
+    assert!(matches!(err.root(), bitcask::Error::Decryption { .. }));
+
+    // rotating to a new key, running it again skips the rotated files:
+    bitcask::rotate_key(
+        &options(Some([1; 32])),
+        Some(bitcask::EncryptionKey([2; 32])),
+    )
+    .unwrap();
+    bitcask::rotate_key(
+        &options(Some([1; 32])),
+        Some(bitcask::EncryptionKey([2; 32])),
+    )
+    .unwrap();
+    assert!(bitcask::new(options(Some([1; 32]))).is_err());
+
+    let db = bitcask::new(options(Some([2; 32]))).unwrap();
+    assert_eq!(b"Paul secret".to_vec(), db.read(b"name").unwrap());
+    assert_eq!(b"42 secret".to_vec(), db.read(b"age").unwrap());
+    assert!(db.read(b"city").is_err());
+    drop(db);
+    assert!(!contains_secret());
+
+    // and back to plain files:
+    bitcask::rotate_key(&options(Some([2; 32])), None).unwrap();
+    let db = bitcask::new(options(None)).unwrap();
+    assert_eq!(b"Paul secret".to_vec(), db.read(b"name").unwrap());
+    assert!(db.read(b"city").is_err());
+    drop(db);
+    assert!(contains_secret());
+
+    let _ = std::fs::remove_dir_all(&base_dir);
+}
+
+#[test]
+fn incremental_merge_should_only_touch_the_given_files() {
+    let mut db = common::DatabaseTesting::new("db24".to_owned(), ByteSize::b(1).as_u64());
+    let file_id = |path: &std::path::PathBuf| -> u128 {
+        path.extension().unwrap().to_str().unwrap().parse().unwrap()
+    };
+
+    db.write(b"name", b"Peter").unwrap();
+    db.write(b"city", b"Berlin").unwrap();
+    db.write(b"age", b"42").unwrap();
+    db.write(b"name", b"Paul").unwrap();
+    db.remove(b"city").unwrap();
+    db.write(b"zip", b"10115").unwrap();
+    db.write(b"age", b"43").unwrap();
+
+    let immutable = db.stats().num_immutable_datafiles;
+    let paths = db.data_file_paths();
+    let first = paths[0].clone();
+    let merged_ids: Vec<u128> = paths[paths.len() - 3..paths.len() - 1]
+        .iter()
+        .map(file_id)
+        .collect();
+
+    // the newest immutable files hold the delete of 'city', its put stays in an older file:
+    db.merge_files(&merged_ids).unwrap();
+    assert_eq!(immutable - 1, db.stats().num_immutable_datafiles);
+    assert!(first.exists());
+    let remaining: Vec<u128> = db.data_file_paths().iter().map(file_id).collect();
+    assert!(merged_ids.iter().all(|id| !remaining.contains(id)));
+
+    let check = |db: &common::DatabaseTesting| {
+        assert_eq!(b"Paul".to_vec(), db.read(b"name").unwrap());
+        assert_eq!(b"43".to_vec(), db.read(b"age").unwrap());
+        assert_eq!(b"10115".to_vec(), db.read(b"zip").unwrap());
+        assert!(db.read(b"city").is_err());
+    };
+    check(&db);
+
+    db.disable_cleanup();
+    drop(db);
+    let db = common::DatabaseTesting::open("db24".to_owned(), ByteSize::b(1).as_u64());
+    check(&db);
+
+    db.merge_incremental(100).unwrap();
+    assert_eq!(1, db.stats().num_immutable_datafiles);
+    assert!(!first.exists());
+    check(&db);
+
+    let mut db = db;
+    db.disable_cleanup();
+    drop(db);
+    let db = common::DatabaseTesting::open("db24".to_owned(), ByteSize::b(1).as_u64());
+    check(&db);
+    assert_eq!(3, db.keys().count());
+}
+
+#[test]
+fn stats_should_account_live_and_dead_records_per_data_file() {
+    let mut db = common::DatabaseTesting::new("db25".to_owned(), ByteSize::mb(1).as_u64());
+
+    db.write(b"name", b"Peter").unwrap(); // 49 bytes
+    db.write(b"name", b"Paul").unwrap(); // 48 bytes
+    db.write(b"city", b"Berlin").unwrap(); // 50 bytes
+    db.remove(b"city").unwrap(); // 44 bytes
+    db.write(b"age", b"42").unwrap(); // 45 bytes
+
+    let check = |df: &bitcask::DataFileMetadata, live_keys, dead_keys, live_bytes| {
+        assert_eq!(live_keys, df.live_keys, "live keys");
+        assert_eq!(dead_keys, df.dead_keys, "dead keys");
+        assert_eq!(live_bytes, df.live_bytes, "live bytes");
+        assert_eq!(236, df.total_bytes, "total bytes");
+        assert_eq!(236 - live_bytes, df.dead_bytes(), "dead bytes");
+    };
+
+    let stats = db.stats();
+    assert_eq!(1, stats.data_files.len());
+    check(&stats.data_files[0], 2, 3, 93);
+
+    // startup comes to the same numbers:
+    db.disable_cleanup();
+    drop(db);
+    let db = common::DatabaseTesting::open("db25".to_owned(), ByteSize::mb(1).as_u64());
+    let stats = db.stats();
+    assert_eq!(2, stats.data_files.len());
+    check(&stats.data_files[0], 2, 3, 93);
+    assert_eq!(0, stats.data_files[1].total_bytes);
+
+    // overwriting a key kills its record in the immutable file:
+    db.write(b"age", b"43").unwrap();
+    let stats = db.stats();
+    check(&stats.data_files[0], 1, 4, 48);
+    assert_eq!(1, stats.data_files[1].live_keys);
+    assert!(stats.data_files[0].fragmentation() > 0.75);
+}
+
+#[test]
+fn merge_policy_should_merge_in_the_background_unless_paused() {
+    use std::time::Duration;
+
+    let policy = bitcask::MergePolicy {
+        immutable_files: Some(3),
+        check_interval: Duration::from_millis(20),
+        ..Default::default()
+    };
+    let db = common::DatabaseTesting::new_with_options(
+        "db26".to_owned(),
+        bitcask::Options {
+            data_file_limit: 1,
+            merge_policy: Some(policy.clone()),
+            ..Default::default()
+        },
+    );
+
+    db.pause_merges();
+    for n in 0..8u8 {
+        db.write(b"key", &[n]).unwrap();
+        db.write(&[n], b"value").unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(200));
+    assert!(db.stats().num_immutable_datafiles >= 3);
+
+    db.resume_merges();
+    let started = std::time::Instant::now();
+    while db.stats().num_immutable_datafiles > 1 {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "no merge happened"
+        );
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(vec![7], db.read(b"key").unwrap());
+    assert_eq!(9, db.keys().count());
+
+    // the triggers and the time window:
+    let data_files = db.stats().data_files;
+    assert!(!policy.is_due(&data_files[..1]));
+    let dead_bytes = bitcask::MergePolicy {
+        dead_bytes: Some(1),
+        ..Default::default()
+    };
+    assert!(!dead_bytes.is_due(&[]));
+
+    let at = |hour: u64| hour * 3600 + 59;
+    let window = |start, end| bitcask::MergePolicy {
+        window: Some((start, end)),
+        ..Default::default()
+    };
+    assert!(window(2, 5).is_in_window(at(2)));
+    assert!(!window(2, 5).is_in_window(at(5)));
+    assert!(window(22, 4).is_in_window(at(23)));
+    assert!(window(22, 4).is_in_window(at(24 + 3)));
+    assert!(!window(22, 4).is_in_window(at(12)));
+    assert!(window(3, 3).is_in_window(at(12)));
+}
+
+#[test]
+fn interrupted_merges_should_be_finished_or_rolled_back_at_startup() {
+    let mut db = common::DatabaseTesting::new("db27".to_owned(), ByteSize::b(1).as_u64());
+    let file_id = |path: &std::path::PathBuf| -> u128 {
+        path.extension().unwrap().to_str().unwrap().parse().unwrap()
+    };
+
+    db.write(b"name", b"Peter").unwrap();
+    db.write(b"city", b"Berlin").unwrap();
+    db.write(b"age", b"42").unwrap();
+
+    let check = |db: &common::DatabaseTesting| {
+        assert_eq!(b"Peter".to_vec(), db.read(b"name").unwrap());
+        assert_eq!(b"Berlin".to_vec(), db.read(b"city").unwrap());
+        assert_eq!(b"42".to_vec(), db.read(b"age").unwrap());
+    };
+
+    let paths = db.data_file_paths();
+    let base_dir = paths[0].parent().unwrap().to_path_buf();
+    let first = paths[0].clone();
+    let replaced = file_id(&first);
+    let merged = replaced + 1;
+    db.disable_cleanup();
+    drop(db);
+
+    // a merge which went down after its manifest was written gets finished:
+    let merged_path = base_dir.join(format!("merge.{}", merged));
+    std::fs::copy(&first, &merged_path).unwrap();
+    std::fs::write(
+        base_dir.join("merge-manifest"),
+        format!("merged {}\nreplaced {}\n", merged, replaced),
+    )
+    .unwrap();
+
+    let mut db = common::DatabaseTesting::open("db27".to_owned(), ByteSize::b(1).as_u64());
+    check(&db);
+    assert!(!first.exists());
+    assert!(!merged_path.exists());
+    assert!(base_dir.join(format!("data.{}", merged)).exists());
+    assert!(!base_dir.join("merge-manifest").exists());
+    let num_data_files = db.count_all_data_files();
+    db.disable_cleanup();
+    drop(db);
+
+    // one without a manifest gets rolled back:
+    let orphan = merged + 2;
+    let orphan_path = base_dir.join(format!("merge.{}", orphan));
+    let orphan_index = base_dir.join(format!("index.{}", orphan));
+    std::fs::copy(base_dir.join(format!("data.{}", merged)), &orphan_path).unwrap();
+    std::fs::copy(base_dir.join(format!("index.{}", merged)), &orphan_index).unwrap();
+    std::fs::write(base_dir.join("merge-manifest.tmp"), "merged").unwrap();
+
+    let db = common::DatabaseTesting::open("db27".to_owned(), ByteSize::b(1).as_u64());
+    check(&db);
+    assert!(!orphan_path.exists());
+    assert!(!orphan_index.exists());
+    assert!(!base_dir.join("merge-manifest.tmp").exists());
+    // the empty active file of the last run got replaced by a new one, no data file was added:
+    assert_eq!(num_data_files, db.count_all_data_files());
+
+    // a completed merge leaves neither merge files nor a manifest behind:
+    db.merge().unwrap();
+    check(&db);
+    let leftovers = std::fs::read_dir(&base_dir)
+        .unwrap()
+        .filter(|entry| {
+            let name = entry.as_ref().unwrap().file_name();
+            name.to_str().unwrap().starts_with("merge")
+        })
+        .count();
+    assert_eq!(0, leftovers);
+}
+
+#[test]
+fn background_merges_should_keep_writes_made_meanwhile() {
+    let mut db = common::DatabaseTesting::new("db28".to_owned(), ByteSize::b(1).as_u64());
+
+    for n in 0..200u32 {
+        db.write(&n.to_be_bytes(), b"old").unwrap();
+    }
+
+    let merge = db.merge_in_background().unwrap();
+    for n in 0..100u32 {
+        db.write(&n.to_be_bytes(), b"new").unwrap();
+    }
+    for n in 100..150u32 {
+        db.remove(&n.to_be_bytes()).unwrap();
+    }
+    merge.join().unwrap().unwrap();
+
+    let check = |db: &common::DatabaseTesting| {
+        assert_eq!(150, db.keys().count());
+        for n in 0..200u32 {
+            let value = db.read(&n.to_be_bytes());
+            match n {
+                0..=99 => assert_eq!(b"new".to_vec(), value.unwrap()),
+                100..=149 => assert!(matches!(value, Err(bitcask::Error::NotFound { .. }))),
+                _ => assert_eq!(b"old".to_vec(), value.unwrap()),
+            }
+        }
+    };
+    check(&db);
+
+    db.disable_cleanup();
+    drop(db);
+    let db = common::DatabaseTesting::open("db28".to_owned(), ByteSize::b(1).as_u64());
+    check(&db);
+
+    // merging again drops the records which got superseded during the first merge:
+    db.merge().unwrap();
+    check(&db);
+    let stats = db.stats();
+    let merged = &stats.data_files[0];
+    assert_eq!(150, merged.live_keys);
+    assert_eq!(0, merged.dead_keys);
+}
+
+#[test]
+fn merges_should_purge_tombstones_which_hide_nothing() {
+    let db = common::DatabaseTesting::new("db29".to_owned(), ByteSize::b(1).as_u64());
+    let file_id = |path: &std::path::PathBuf| -> u128 {
+        path.extension().unwrap().to_str().unwrap().parse().unwrap()
+    };
+
+    // the second record of a file starts a new one, plain removes never do:
+    db.write(b"a", b"1").unwrap(); // file 1
+    db.write(b"b", b"2").unwrap(); // file 1
+    db.remove(b"a").unwrap(); // file 2
+    db.write(b"c", b"3").unwrap(); // file 2
+    db.remove(b"b").unwrap(); // file 3
+    db.write(b"d", b"4").unwrap(); // file 3
+
+    let check = |db: &common::DatabaseTesting| {
+        assert!(db.read(b"a").is_err());
+        assert!(db.read(b"b").is_err());
+        assert_eq!(b"3".to_vec(), db.read(b"c").unwrap());
+        assert_eq!(b"4".to_vec(), db.read(b"d").unwrap());
+    };
+
+    // the delete of 'a' still hides its put in file 1:
+    let paths = db.data_file_paths();
+    let report = db.merge_files(&[file_id(&paths[1])]).unwrap();
+    assert_eq!(1, report.files_merged);
+    assert_eq!(0, report.tombstones_purged);
+    assert_eq!(1, report.tombstones_kept);
+    check(&db);
+
+    // the put and the delete of 'b' are merged together:
+    let paths = db.data_file_paths();
+    let report = db
+        .merge_files(&[file_id(&paths[0]), file_id(&paths[1])])
+        .unwrap();
+    assert_eq!(1, report.tombstones_purged);
+    assert_eq!(0, report.tombstones_kept);
+    check(&db);
+
+    // without any other file left, the tombstone of 'a' goes as well:
+    let report = db.merge().unwrap();
+    assert_eq!(1, report.tombstones_purged);
+    assert_eq!(0, report.tombstones_kept);
+    check(&db);
+    assert_eq!(0, db.stats().data_files[0].dead_keys);
+
+    let mut db = db;
+    db.disable_cleanup();
+    drop(db);
+    let db = common::DatabaseTesting::open("db29".to_owned(), ByteSize::b(1).as_u64());
+    check(&db);
+}
+
+#[test]
+fn throttled_merges_should_report_their_progress() {
+    use std::time::{Duration, Instant};
+
+    let db = common::DatabaseTesting::new_with_options(
+        "db30".to_owned(),
+        bitcask::Options {
+            data_file_limit: 1,
+            merge_throttle: bitcask::MergeThrottle {
+                records_per_sec: Some(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+
+    for n in 0..50u32 {
+        db.write(&n.to_be_bytes(), b"value").unwrap();
+    }
+    assert!(db.merge_progress().is_none());
+
+    let started = Instant::now();
+    let merge = db.merge_in_background().unwrap();
+
+    let mut progress = None;
+    while progress.is_none() {
+        assert!(started.elapsed() < Duration::from_secs(5), "no progress");
+        progress = db.merge_progress().filter(|p| p.records_processed > 0);
+    }
+    let progress = progress.unwrap();
+    assert!(progress.records_total >= 49);
+    assert!(progress.bytes_total > 0);
+    assert!(progress.bytes_remaining() < progress.bytes_total);
+    assert!(progress.eta().is_some());
+
+    // 50 records at 100 per second take about half a second:
+    merge.join().unwrap().unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(400));
+    assert!(db.merge_progress().is_none());
+    assert_eq!(50, db.keys().count());
+}
+
+#[test]
+fn cancelled_merges_should_leave_the_store_as_it_was() {
+    let db = common::DatabaseTesting::new("db31".to_owned(), ByteSize::b(1).as_u64());
+
+    for n in 0..20u32 {
+        db.write(&n.to_be_bytes(), b"old").unwrap();
+        db.write(&n.to_be_bytes(), b"new").unwrap();
+    }
+    let check = |db: &common::DatabaseTesting| {
+        assert_eq!(20, db.keys().count());
+        for n in 0..20u32 {
+            assert_eq!(b"new".to_vec(), db.read(&n.to_be_bytes()).unwrap());
+        }
+    };
+    let files = |db: &common::DatabaseTesting| {
+        let base_dir = db.data_file_paths()[0].parent().unwrap().to_path_buf();
+        let mut names: Vec<String> = std::fs::read_dir(base_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    };
+    let before = files(&db);
+
+    // cancelled halfway through:
+    let cancel = bitcask::CancellationToken::new();
+    let mut keys_rewritten = 0;
+    let result = db.merge_with(&cancel, |progress| {
+        keys_rewritten = progress.keys_rewritten;
+        if progress.keys_rewritten == 10 {
+            cancel.cancel();
+        }
+    });
+    assert!(matches!(result, Err(bitcask::Error::Cancelled)));
+    assert_eq!(10, keys_rewritten);
+    assert_eq!(before, files(&db));
+    assert!(db.merge_progress().is_none());
+    check(&db);
+
+    // to the end, with a progress update per data file:
+    let mut files_done = Vec::new();
+    let mut last = None;
+    let report = db
+        .merge_with(&bitcask::CancellationToken::new(), |progress| {
+            if files_done.last() != Some(&progress.files_done) {
+                files_done.push(progress.files_done);
+            }
+            last = Some(progress.clone());
+        })
+        .unwrap();
+    let last = last.unwrap();
+    assert_eq!(last.files_total, *files_done.last().unwrap());
+    assert_eq!((0..=last.files_total).collect::<Vec<_>>(), files_done);
+    assert_eq!(20, last.keys_rewritten);
+    assert!(report.bytes_reclaimed > 0);
+    assert_eq!(report.bytes_reclaimed, last.bytes_reclaimed);
+    check(&db);
+}
+
+#[test]
+fn compare_and_swap_should_only_write_the_expected_value() {
+    use std::time::Duration;
+
+    let mut db = common::DatabaseTesting::new("db32".to_owned(), ByteSize::b(100).as_u64());
+
+    assert!(db.put_if_absent(b"name", b"Peter").unwrap());
+    assert!(!db.put_if_absent(b"name", b"Paul").unwrap());
+    assert_eq!(b"Peter".to_vec(), db.read(b"name").unwrap());
+
+    assert!(db
+        .compare_and_swap(b"name", Some(b"Peter"), Some(b"Paul"))
+        .unwrap());
+    assert!(!db
+        .compare_and_swap(b"name", Some(b"Peter"), Some(b"Susi"))
+        .unwrap());
+    assert!(!db.compare_and_swap(b"name", None, Some(b"Susi")).unwrap());
+    assert_eq!(b"Paul".to_vec(), db.read(b"name").unwrap());
+
+    assert!(db.compare_and_swap(b"name", Some(b"Paul"), None).unwrap());
+    assert!(db.read(b"name").is_err());
+    assert!(db.compare_and_swap(b"name", None, Some(b"Susi")).unwrap());
+
+    // expired keys are absent:
+    db.write_with_ttl(b"session", b"1", Duration::from_millis(1))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+    assert!(db.put_if_absent(b"session", b"2").unwrap());
+
+    // concurrent increments don't get lost:
+    db.write(b"counter", &0u32.to_be_bytes()).unwrap();
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let db: bitcask::Database = (*db).clone();
+            std::thread::spawn(move || {
+                let mut swaps = 0;
+                while swaps < 50 {
+                    let current = db.read(b"counter").unwrap();
+                    let mut bytes = [0u8; 4];
+                    bytes.copy_from_slice(&current);
+                    let next = u32::from_be_bytes(bytes) + 1;
+                    if db
+                        .compare_and_swap(b"counter", Some(&current), Some(&next.to_be_bytes()))
+                        .unwrap()
+                    {
+                        swaps += 1;
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(400u32.to_be_bytes().to_vec(), db.read(b"counter").unwrap());
+
+    db.disable_cleanup();
+    drop(db);
+    let db = common::DatabaseTesting::open("db32".to_owned(), ByteSize::b(100).as_u64());
+    assert_eq!(b"Susi".to_vec(), db.read(b"name").unwrap());
+    assert_eq!(b"2".to_vec(), db.read(b"session").unwrap());
+    assert_eq!(400u32.to_be_bytes().to_vec(), db.read(b"counter").unwrap());
+}
+
+#[test]
+fn torn_writes_should_be_cut_off_after_a_merge() {
+    let mut db = common::DatabaseTesting::new("db33".to_owned(), ByteSize::b(1).as_u64());
+
+    db.write(b"a", b"1").unwrap();
+    db.write(b"b", b"2").unwrap();
+    db.write(b"c", b"3").unwrap();
+    db.write(b"d", b"4").unwrap();
+    db.merge().unwrap();
+    // the merged data file has a higher id than the active one:
+    db.write(b"e", b"5").unwrap();
+    db.disable_cleanup();
+
+    let paths = db.data_file_paths();
+    let base_dir = paths[0].parent().unwrap().to_path_buf();
+    drop(db);
+
+    // the active file of the last run is the one without a hint file:
+    let active = paths
+        .iter()
+        .find(|path| {
+            let file_id = path.extension().unwrap().to_str().unwrap();
+            !base_dir.join(format!("index.{}", file_id)).exists()
+        })
+        .unwrap();
+    assert_ne!(active, paths.last().unwrap());
+
+    let mut bytes = std::fs::read(active).unwrap();
+    let len = bytes.len();
+    let partial_record = bytes[38..50].to_vec();
+    bytes.extend_from_slice(&partial_record);
+    std::fs::write(active, bytes).unwrap();
+
+    let check = |db: &common::DatabaseTesting| {
+        for (key, value) in [(b"a", b"1"), (b"c", b"3"), (b"e", b"5")] {
+            assert_eq!(value.to_vec(), db.read(key).unwrap());
+        }
+        assert_eq!(5, db.stats().num_keys);
+    };
+
+    // readers skip what is still in flight:
+    let reader = common::DatabaseTesting::try_open_read_only("db33".to_owned()).unwrap();
+    check(&reader);
+    drop(reader);
+
+    let db = common::DatabaseTesting::open("db33".to_owned(), ByteSize::b(1).as_u64());
+    check(&db);
+    assert_eq!(len as u64, std::fs::metadata(active).unwrap().len());
+}
+
+#[test]
+fn close_should_stop_a_running_merge() {
+    use std::time::{Duration, Instant};
+
+    let mut db = common::DatabaseTesting::new_with_options(
+        "db34".to_owned(),
+        bitcask::Options {
+            data_file_limit: 1,
+            merge_throttle: bitcask::MergeThrottle {
+                records_per_sec: Some(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+
+    for n in 0..20u32 {
+        db.write(&n.to_be_bytes(), b"value").unwrap();
+    }
+
+    let started = Instant::now();
+    let merge = db.merge_in_background().unwrap();
+    while db.merge_progress().is_none() {
+        assert!(started.elapsed() < Duration::from_secs(5), "no progress");
+    }
+
+    // close waits for the merge, which gives up before it replaces any file:
+    db.close().unwrap();
+    assert!(matches!(
+        merge.join().unwrap().err().unwrap(),
+        bitcask::Error::Closed
+    ));
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    db.disable_cleanup();
+    let db2 = common::DatabaseTesting::open("db34".to_owned(), ByteSize::b(1).as_u64());
+    assert_eq!(20, db2.keys().count());
+    for n in 0..20u32 {
+        assert_eq!(b"value".to_vec(), db2.read(&n.to_be_bytes()).unwrap());
+    }
+    drop(db);
+}
+
+#[test]
+fn corrupted_lengths_should_not_be_taken_for_torn_writes() {
+    let mut db = common::DatabaseTesting::new("db35".to_owned(), ByteSize::mb(1).as_u64());
+
+    db.write(b"a", b"1").unwrap();
+    db.write(b"b", b"2").unwrap();
+    db.write(b"c", b"3").unwrap();
+    let path = db.data_file_paths().remove(0);
+    db.disable_cleanup();
+    drop(db);
+
+    // the key length of the first record, after the header, crc, kind and timestamp,
+    // now reaches past the end of the file:
+    let mut bytes = std::fs::read(&path).unwrap();
+    let len = bytes.len();
+    bytes[38 + 24..38 + 32].copy_from_slice(&1000u64.to_le_bytes());
+    std::fs::write(&path, bytes).unwrap();
+
+    let err = common::DatabaseTesting::try_open_read_only("db35".to_owned())
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err.root(), bitcask::Error::Corruption { .. }),
+        "{}",
+        err
+    );
+    let err = common::DatabaseTesting::try_open("db35".to_owned(), ByteSize::mb(1).as_u64())
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err.root(), bitcask::Error::Corruption { .. }),
+        "{}",
+        err
+    );
+    assert_eq!(len as u64, std::fs::metadata(&path).unwrap().len());
+
+    let _ = std::fs::remove_dir_all("./data/db35");
+}
+
+#[test]
+fn plain_files_should_be_refused_with_an_encryption_key() {
+    let base_dir = std::path::PathBuf::from("./data/db36");
+    let _ = std::fs::remove_dir_all(&base_dir);
+    let options = |key: Option<[u8; 32]>, allow_plain_files: bool| bitcask::Options {
+        base_dir: base_dir.clone(),
+        data_file_limit: 1,
+        encryption_key: key.map(bitcask::EncryptionKey),
+        allow_plain_files,
+        ..Default::default()
+    };
+
+    let db = bitcask::new(options(None, false)).unwrap();
+    db.write(b"name", b"Peter").unwrap();
+    db.write(b"city", b"Berlin").unwrap();
+    drop(db);
+
+    let err = bitcask::new(options(Some([1; 32]), false)).err().unwrap();
+    assert!(matches!(err.root(), bitcask::Error::UnencryptedFile { .. }));
+
+    // while migrating plain and encrypted files are mixed:
+    let db = bitcask::new(options(Some([1; 32]), true)).unwrap();
+    assert_eq!(b"Peter".to_vec(), db.read(b"name").unwrap());
+    db.write(b"age", b"42").unwrap();
+    drop(db);
+
+    bitcask::rotate_key(
+        &options(Some([1; 32]), false),
+        Some(bitcask::EncryptionKey([1; 32])),
+    )
+    .unwrap();
+    let db = bitcask::new(options(Some([1; 32]), false)).unwrap();
+    assert_eq!(b"Peter".to_vec(), db.read(b"name").unwrap());
+    assert_eq!(b"Berlin".to_vec(), db.read(b"city").unwrap());
+    assert_eq!(b"42".to_vec(), db.read(b"age").unwrap());
+    drop(db);
+
+    let _ = std::fs::remove_dir_all(&base_dir);
+}
+
+#[test]
+fn close_should_not_wait_for_the_merge_throttle() {
+    use std::time::{Duration, Instant};
+
+    let mut db = common::DatabaseTesting::new_with_options(
+        "db37".to_owned(),
+        bitcask::Options {
+            data_file_limit: 1,
+            merge_throttle: bitcask::MergeThrottle {
+                bytes_per_sec: Some(1000),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+    );
+
+    // each record takes the throttle about 5 seconds:
+    let value = vec![b'x'; 5000];
+    for n in 0..4u32 {
+        db.write(&n.to_be_bytes(), &value).unwrap();
+    }
+
+    let merge = db.merge_in_background().unwrap();
+    let started = Instant::now();
+    while db.merge_progress().is_none() {
+        assert!(started.elapsed() < Duration::from_secs(5), "no progress");
+    }
+    std::thread::sleep(Duration::from_millis(50));
+
+    let closing = Instant::now();
+    db.close().unwrap();
+    assert!(
+        closing.elapsed() < Duration::from_millis(500),
+        "close took {:?}",
+        closing.elapsed()
+    );
+    assert!(matches!(
+        merge.join().unwrap().err().unwrap(),
+        bitcask::Error::Closed
+    ));
+
+    db.disable_cleanup();
+    let db2 = common::DatabaseTesting::open("db37".to_owned(), ByteSize::b(1).as_u64());
+    assert_eq!(4, db2.keys().count());
+    drop(db);
+}
+
+#[test]
+fn foreign_files_should_be_refused_and_left_alone() {
+    let mut db = common::DatabaseTesting::new("db38".to_owned(), ByteSize::mb(1).as_u64());
+    db.write(b"name", b"Peter").unwrap();
+    db.disable_cleanup();
+    drop(db);
+
+    // a data file name with an id, but neither a header nor a baseline record:
+    let content = b"this is not a data file, but it has got a valid name".to_vec();
+    std::fs::write("./data/db38/data.5", &content).unwrap();
+
+    let err = common::DatabaseTesting::try_open("db38".to_owned(), ByteSize::mb(1).as_u64())
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err.root(), bitcask::Error::InvalidFormat { .. }),
+        "foreign file accepted"
+    );
+    assert_eq!(content, std::fs::read("./data/db38/data.5").unwrap());
+    assert!(!std::path::Path::new("./data/db38/index.5").exists());
+    assert!(!std::path::Path::new("./data/db38/quarantine.5").exists());
+
+    let _ = std::fs::remove_dir_all("./data/db38");
+}
+
+#[test]
+fn baseline_files_should_never_be_cut_off() {
+    let _ = std::fs::remove_dir_all("./data/db39");
+    std::fs::create_dir_all("./data/db39").unwrap();
+
+    // without checksums a partial record can't be told from a corrupted one:
+    let mut bytes = bincode::serialize(&(1u128, b"name".to_vec(), b"Peter".to_vec())).unwrap();
+    let partial = bincode::serialize(&(2u128, b"city".to_vec(), b"Berlin".to_vec())).unwrap();
+    bytes.extend_from_slice(&partial[..partial.len() - 3]);
+    std::fs::write("./data/db39/data.1", &bytes).unwrap();
+
+    let err = common::DatabaseTesting::try_open("db39".to_owned(), ByteSize::mb(1).as_u64())
+        .err()
+        .unwrap();
+    assert!(
+        matches!(err.root(), bitcask::Error::TruncatedRecord { .. }),
+        "{}",
+        err
+    );
+    assert_eq!(bytes, std::fs::read("./data/db39/data.1").unwrap());
+    assert!(!std::path::Path::new("./data/db39/quarantine.1").exists());
+
+    let _ = std::fs::remove_dir_all("./data/db39");
+}
+
+#[test]
+fn leftovers_of_an_interrupted_key_rotation_should_be_removed() {
+    let mut db = common::DatabaseTesting::new("db40".to_owned(), ByteSize::mb(1).as_u64());
+    db.write(b"name", b"Peter").unwrap();
+    let path = db.data_file_paths().remove(0);
+    db.disable_cleanup();
+    drop(db);
+
+    // a rotation which went down before it renamed the rewritten file:
+    let rekey_path = path.with_file_name(format!(
+        "rekey.{}",
+        path.extension().unwrap().to_str().unwrap()
+    ));
+    std::fs::write(&rekey_path, b"half of a rewritten data file").unwrap();
+
+    let db = common::DatabaseTesting::open("db40".to_owned(), ByteSize::mb(1).as_u64());
+    assert!(!rekey_path.exists());
+    assert_eq!(b"Peter".to_vec(), db.read(b"name").unwrap());
+}
+
+#[test]
+fn offsets_past_the_end_should_fail_as_corruption() {
+    let db = common::DatabaseTesting::new("db41".to_owned(), ByteSize::mb(1).as_u64());
+    db.write(b"name", b"Peter").unwrap();
+    db.write(b"city", b"Berlin").unwrap();
+    db.sync().unwrap();
+
+    // the file lost both records, the keydir still points to them:
+    let path = db.data_file_paths().remove(0);
+    let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(40).unwrap();
+
+    for key in [&b"name"[..], &b"city"[..]] {
+        let err = db.read(key).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                bitcask::Error::Corruption { .. } | bitcask::Error::TruncatedRecord { .. }
+            ),
+            "{}",
+            err
+        );
+    }
+    assert!(db.get_datafile_at(5).is_err());
+    assert!(db
+        .get_current_datafile()
+        .unwrap()
+        .inspect(false)
+        .contains("Incomplete"));
+}